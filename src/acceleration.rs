@@ -0,0 +1,223 @@
+// Pointer acceleration for relative movement driven by the head.
+//
+// A transfer function maps the speed of the head (angular velocity scaled by
+// the distance to the screen) to a gain that is applied to the movement.
+// The result is fractional, see `output::Subpixel` for how it reaches the
+// screen.
+
+use cgmath::prelude::InnerSpace;
+use cgmath::Vector2;
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+
+// Number of recent speeds that are averaged before the transfer function is
+// applied, to avoid erratic gain from single noisy frames.
+const SPEED_HISTORY: usize = 4;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Curve {
+    // Constant gain.
+    Linear { gain: f32 },
+    // Output grows with speed to the power of `exponent`.
+    Power { gain: f32, exponent: f32 },
+    // Gain moves smoothly from `min_gain` to `max_gain`, reaching the middle
+    // at speed `midpoint`.
+    Sigmoid {
+        min_gain: f32,
+        max_gain: f32,
+        midpoint: f32,
+        steepness: f32,
+    },
+    // Like libinput's adaptive profile: unit gain up to `threshold`, then
+    // growing linearly by `incline` per unit of speed until `max_gain`.
+    Adaptive {
+        threshold: f32,
+        incline: f32,
+        max_gain: f32,
+    },
+    // Gain interpolated linearly between (speed, gain) points, which must be
+    // sorted by speed. Beyond the outermost points, gain stays constant.
+    Piecewise { points: Vec<[f32; 2]> },
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve::Adaptive {
+            threshold: 5f32,
+            incline: 0.1f32,
+            max_gain: 1.5f32,
+        }
+    }
+}
+
+impl Curve {
+    pub fn gain(&self, speed: f32) -> f32 {
+        match *self {
+            Curve::Linear { gain } => gain,
+            Curve::Power { gain, exponent } => {
+                if speed <= 0f32 {
+                    0f32
+                } else {
+                    gain * speed.powf(exponent - 1f32)
+                }
+            }
+            Curve::Sigmoid {
+                min_gain,
+                max_gain,
+                midpoint,
+                steepness,
+            } => min_gain + (max_gain - min_gain) / (1f32 + (-steepness * (speed - midpoint)).exp()),
+            Curve::Adaptive {
+                threshold,
+                incline,
+                max_gain,
+            } => {
+                if speed < threshold {
+                    1f32
+                } else {
+                    (1f32 + incline * (speed - threshold)).min(max_gain)
+                }
+            }
+            Curve::Piecewise { ref points } => piecewise(points, speed),
+        }
+    }
+}
+
+fn piecewise(points: &[[f32; 2]], speed: f32) -> f32 {
+    let first = match points.first() {
+        Some(first) => first,
+        None => return 1f32,
+    };
+    if speed <= first[0] {
+        return first[1];
+    }
+    for pair in points.windows(2) {
+        let ([x0, y0], [x1, y1]) = (pair[0], pair[1]);
+        if speed <= x1 {
+            if x1 == x0 {
+                return y1;
+            }
+            return y0 + (y1 - y0) * (speed - x0) / (x1 - x0);
+        }
+    }
+    points[points.len() - 1][1]
+}
+
+pub struct Accelerator {
+    curve: Curve,
+    speeds: VecDeque<f32>,
+}
+
+impl Accelerator {
+    pub fn new(curve: Curve) -> Accelerator {
+        Accelerator {
+            curve,
+            speeds: VecDeque::with_capacity(SPEED_HISTORY),
+        }
+    }
+
+    // Takes the movement of one frame and returns the accelerated movement.
+    pub fn apply(&mut self, movement: Vector2<f32>) -> Vector2<f32> {
+        if self.speeds.len() == SPEED_HISTORY {
+            self.speeds.pop_front();
+        }
+        self.speeds.push_back(movement.magnitude());
+        let speed = self.speeds.iter().sum::<f32>() / self.speeds.len() as f32;
+
+        movement * self.curve.gain(speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::vec2;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn linear() {
+        let curve = Curve::Linear { gain: 1.5f32 };
+        assert!(close(curve.gain(0f32), 1.5f32));
+        assert!(close(curve.gain(100f32), 1.5f32));
+    }
+
+    #[test]
+    fn power() {
+        let curve = Curve::Power {
+            gain: 2f32,
+            exponent: 2f32,
+        };
+        assert!(close(curve.gain(0f32), 0f32));
+        assert!(close(curve.gain(3f32), 6f32));
+        assert!(close(curve.gain(0.5f32), 1f32));
+    }
+
+    #[test]
+    fn sigmoid() {
+        let curve = Curve::Sigmoid {
+            min_gain: 0.5f32,
+            max_gain: 2.5f32,
+            midpoint: 10f32,
+            steepness: 1f32,
+        };
+        assert!(close(curve.gain(10f32), 1.5f32));
+        assert!(close(curve.gain(-100f32), 0.5f32));
+        assert!(close(curve.gain(100f32), 2.5f32));
+        assert!(curve.gain(9f32) < curve.gain(11f32));
+    }
+
+    #[test]
+    fn adaptive() {
+        let curve = Curve::default();
+        assert!(close(curve.gain(0f32), 1f32));
+        assert!(close(curve.gain(4.9f32), 1f32));
+        assert!(close(curve.gain(7f32), 1.2f32));
+        assert!(close(curve.gain(100f32), 1.5f32));
+    }
+
+    #[test]
+    fn piecewise_interpolates() {
+        let curve = Curve::Piecewise {
+            points: vec![[1f32, 1f32], [3f32, 2f32], [5f32, 4f32]],
+        };
+        assert!(close(curve.gain(1f32), 1f32));
+        assert!(close(curve.gain(2f32), 1.5f32));
+        assert!(close(curve.gain(3f32), 2f32));
+        assert!(close(curve.gain(4.5f32), 3.5f32));
+    }
+
+    #[test]
+    fn piecewise_clamps() {
+        let points = vec![[1f32, 1f32], [3f32, 2f32]];
+        assert!(close(piecewise(&points, 0f32), 1f32));
+        assert!(close(piecewise(&points, 3.5f32), 2f32));
+        assert!(close(piecewise(&points, 1000f32), 2f32));
+        // A vertical step takes the upper gain.
+        let step = vec![[1f32, 1f32], [1f32, 3f32]];
+        assert!(close(piecewise(&step, 1f32), 1f32));
+        assert!(close(piecewise(&step, 2f32), 3f32));
+        assert!(close(piecewise(&[], 5f32), 1f32));
+    }
+
+    #[test]
+    fn averages_recent_speeds() {
+        // Gain is a tenth of the speed.
+        let mut accelerator = Accelerator::new(Curve::Piecewise {
+            points: vec![[0f32, 0f32], [10f32, 1f32]],
+        });
+        let moved = accelerator.apply(vec2(4f32, 0f32));
+        assert!(close(moved.x, 1.6f32), "{:?}", moved);
+        for _ in 0..SPEED_HISTORY {
+            accelerator.apply(vec2(0f32, 10f32));
+        }
+        // (10 + 10 + 10 + 2) / 4 = 8, the first speed has dropped out.
+        let moved = accelerator.apply(vec2(0f32, 2f32));
+        assert!(close(moved.y, 1.6f32), "{:?}", moved);
+        assert!(close(moved.x, 0f32));
+    }
+}
@@ -0,0 +1,302 @@
+// Attitude and heading reference system (AHRS) filters that fuse the
+// accelerometer, gyroscope and magnetometer streams of the IMU into an
+// orientation quaternion.
+//
+// See:
+//  * https://x-io.co.uk/open-source-imu-and-ahrs-algorithms/
+//  * Madgwick, "An efficient orientation filter for inertial and
+//    inertial/magnetic sensor arrays", 2010.
+//  * Mahony et al., "Nonlinear Complementary Filters on the Special
+//    Orthogonal Group", 2008.
+
+use cgmath::prelude::InnerSpace;
+use cgmath::{vec3, Quaternion, Vector3};
+
+pub trait Ahrs {
+    // Advance the filter by `dt` seconds. `gyro` is in [rad / s], `accel` and
+    // `mag` may be in any unit since they are normalized before use. Pass
+    // `None` for `mag` to only fuse gyroscope and accelerometer (no heading
+    // correction, so yaw will slowly drift).
+    fn update(
+        &mut self,
+        gyro: Vector3<f32>,
+        accel: Vector3<f32>,
+        mag: Option<Vector3<f32>>,
+        dt: f32,
+    );
+
+    // Orientation of the sensor frame relative to the earth frame.
+    fn orientation(&self) -> Quaternion<f32>;
+}
+
+fn identity() -> Quaternion<f32> {
+    Quaternion::new(1f32, 0f32, 0f32, 0f32)
+}
+
+fn is_zero(v: Vector3<f32>) -> bool {
+    v.x == 0f32 && v.y == 0f32 && v.z == 0f32
+}
+
+pub struct Madgwick {
+    // Gain of the gradient descent step. Larger values converge faster but
+    // let more accelerometer noise through.
+    beta: f32,
+    q: Quaternion<f32>,
+}
+
+impl Madgwick {
+    pub fn new(beta: f32) -> Madgwick {
+        Madgwick { beta, q: identity() }
+    }
+
+    // Objective function gradient for gravity only.
+    fn gradient_imu(&self, a: Vector3<f32>) -> Quaternion<f32> {
+        let (q0, q1, q2, q3) = (self.q.s, self.q.v.x, self.q.v.y, self.q.v.z);
+        let (ax, ay, az) = (a.x, a.y, a.z);
+
+        let (q0q0, q1q1, q2q2, q3q3) = (q0 * q0, q1 * q1, q2 * q2, q3 * q3);
+
+        Quaternion::new(
+            4f32 * q0 * q2q2 + 2f32 * q2 * ax + 4f32 * q0 * q1q1 - 2f32 * q1 * ay,
+            4f32 * q1 * q3q3 - 2f32 * q3 * ax + 4f32 * q0q0 * q1 - 2f32 * q0 * ay - 4f32 * q1
+                + 8f32 * q1 * q1q1
+                + 8f32 * q1 * q2q2
+                + 4f32 * q1 * az,
+            4f32 * q0q0 * q2 + 2f32 * q0 * ax + 4f32 * q2 * q3q3 - 2f32 * q3 * ay - 4f32 * q2
+                + 8f32 * q2 * q1q1
+                + 8f32 * q2 * q2q2
+                + 4f32 * q2 * az,
+            4f32 * q1q1 * q3 - 2f32 * q1 * ax + 4f32 * q2q2 * q3 - 2f32 * q2 * ay,
+        )
+    }
+
+    // Objective function gradient for gravity and the earth magnetic field.
+    fn gradient_marg(&self, a: Vector3<f32>, m: Vector3<f32>) -> Quaternion<f32> {
+        let (q0, q1, q2, q3) = (self.q.s, self.q.v.x, self.q.v.y, self.q.v.z);
+        let (ax, ay, az) = (a.x, a.y, a.z);
+        let (mx, my, mz) = (m.x, m.y, m.z);
+
+        let (q0q0, q0q1, q0q2, q0q3) = (q0 * q0, q0 * q1, q0 * q2, q0 * q3);
+        let (q1q1, q1q2, q1q3) = (q1 * q1, q1 * q2, q1 * q3);
+        let (q2q2, q2q3, q3q3) = (q2 * q2, q2 * q3, q3 * q3);
+
+        // Reference direction of the earth magnetic field.
+        let hx = mx * q0q0 - 2f32 * q0 * my * q3 + 2f32 * q0 * mz * q2 + mx * q1q1
+            + 2f32 * q1 * my * q2
+            + 2f32 * q1 * mz * q3
+            - mx * q2q2
+            - mx * q3q3;
+        let hy = 2f32 * q0 * mx * q3 + my * q0q0 - 2f32 * q0 * mz * q1 + 2f32 * q1 * mx * q2
+            - my * q1q1
+            + my * q2q2
+            + 2f32 * q2 * mz * q3
+            - my * q3q3;
+        let bx2 = (hx * hx + hy * hy).sqrt();
+        let bz2 = -2f32 * q0 * mx * q2 + 2f32 * q0 * my * q1 + mz * q0q0 + 2f32 * q1 * mx * q3
+            - mz * q1q1
+            + 2f32 * q2 * my * q3
+            - mz * q2q2
+            + mz * q3q3;
+        let bx4 = 2f32 * bx2;
+        let bz4 = 2f32 * bz2;
+
+        // Residuals of the objective function.
+        let fa_x = 2f32 * q1q3 - 2f32 * q0q2 - ax;
+        let fa_y = 2f32 * q0q1 + 2f32 * q2q3 - ay;
+        let fa_z = 1f32 - 2f32 * q1q1 - 2f32 * q2q2 - az;
+        let fm_x = bx2 * (0.5f32 - q2q2 - q3q3) + bz2 * (q1q3 - q0q2) - mx;
+        let fm_y = bx2 * (q1q2 - q0q3) + bz2 * (q0q1 + q2q3) - my;
+        let fm_z = bx2 * (q0q2 + q1q3) + bz2 * (0.5f32 - q1q1 - q2q2) - mz;
+
+        Quaternion::new(
+            -2f32 * q2 * fa_x + 2f32 * q1 * fa_y - bz2 * q2 * fm_x
+                + (-bx2 * q3 + bz2 * q1) * fm_y
+                + bx2 * q2 * fm_z,
+            2f32 * q3 * fa_x + 2f32 * q0 * fa_y - 4f32 * q1 * fa_z
+                + bz2 * q3 * fm_x
+                + (bx2 * q2 + bz2 * q0) * fm_y
+                + (bx2 * q3 - bz4 * q1) * fm_z,
+            -2f32 * q0 * fa_x + 2f32 * q3 * fa_y - 4f32 * q2 * fa_z
+                + (-bx4 * q2 - bz2 * q0) * fm_x
+                + (bx2 * q1 + bz2 * q3) * fm_y
+                + (bx2 * q0 - bz4 * q2) * fm_z,
+            2f32 * q1 * fa_x + 2f32 * q2 * fa_y
+                + (-bx4 * q3 + bz2 * q1) * fm_x
+                + (-bx2 * q0 + bz2 * q2) * fm_y
+                + bx2 * q1 * fm_z,
+        )
+    }
+}
+
+impl Ahrs for Madgwick {
+    fn update(
+        &mut self,
+        gyro: Vector3<f32>,
+        accel: Vector3<f32>,
+        mag: Option<Vector3<f32>>,
+        dt: f32,
+    ) {
+        // Rate of change of quaternion from gyroscope.
+        let mut q_dot = self.q * Quaternion::from_sv(0f32, gyro) * 0.5f32;
+
+        if !is_zero(accel) {
+            let a = accel.normalize();
+            let gradient = match mag {
+                Some(m) if !is_zero(m) => self.gradient_marg(a, m.normalize()),
+                _ => self.gradient_imu(a),
+            };
+            if gradient.magnitude() > 0f32 {
+                q_dot = q_dot - gradient.normalize() * self.beta;
+            }
+        }
+
+        self.q = (self.q + q_dot * dt).normalize();
+    }
+
+    fn orientation(&self) -> Quaternion<f32> {
+        self.q
+    }
+}
+
+pub struct Mahony {
+    // Proportional and integral gains of the feedback on the orientation
+    // error.
+    kp: f32,
+    ki: f32,
+    integral: Vector3<f32>,
+    q: Quaternion<f32>,
+}
+
+impl Mahony {
+    pub fn new(kp: f32, ki: f32) -> Mahony {
+        Mahony {
+            kp,
+            ki,
+            integral: vec3(0f32, 0f32, 0f32),
+            q: identity(),
+        }
+    }
+}
+
+impl Ahrs for Mahony {
+    fn update(
+        &mut self,
+        gyro: Vector3<f32>,
+        accel: Vector3<f32>,
+        mag: Option<Vector3<f32>>,
+        dt: f32,
+    ) {
+        let mut gyro = gyro;
+
+        if !is_zero(accel) {
+            let a = accel.normalize();
+            let (q0, q1, q2, q3) = (self.q.s, self.q.v.x, self.q.v.y, self.q.v.z);
+
+            // Estimated direction of gravity, halved.
+            let v = vec3(
+                q1 * q3 - q0 * q2,
+                q0 * q1 + q2 * q3,
+                q0 * q0 - 0.5f32 + q3 * q3,
+            );
+            let mut error = a.cross(v);
+
+            if let Some(m) = mag.filter(|m| !is_zero(*m)) {
+                let m = m.normalize();
+                let (q0q0, q0q1, q0q2, q0q3) = (q0 * q0, q0 * q1, q0 * q2, q0 * q3);
+                let (q1q1, q1q2, q1q3) = (q1 * q1, q1 * q2, q1 * q3);
+                let (q2q2, q2q3, q3q3) = (q2 * q2, q2 * q3, q3 * q3);
+
+                // Reference direction of the earth magnetic field.
+                let hx = 2f32
+                    * (m.x * (0.5f32 - q2q2 - q3q3) + m.y * (q1q2 - q0q3) + m.z * (q1q3 + q0q2));
+                let hy = 2f32
+                    * (m.x * (q1q2 + q0q3) + m.y * (0.5f32 - q1q1 - q3q3) + m.z * (q2q3 - q0q1));
+                let bx = (hx * hx + hy * hy).sqrt();
+                let bz = 2f32
+                    * (m.x * (q1q3 - q0q2) + m.y * (q2q3 + q0q1) + m.z * (0.5f32 - q1q1 - q2q2));
+
+                // Estimated direction of the magnetic field, halved.
+                let w = vec3(
+                    bx * (0.5f32 - q2q2 - q3q3) + bz * (q1q3 - q0q2),
+                    bx * (q1q2 - q0q3) + bz * (q0q1 + q2q3),
+                    bx * (q0q2 + q1q3) + bz * (0.5f32 - q1q1 - q2q2),
+                );
+                error = error + m.cross(w);
+            }
+
+            if self.ki > 0f32 {
+                self.integral = self.integral + error * (2f32 * self.ki * dt);
+                gyro = gyro + self.integral;
+            }
+            gyro = gyro + error * (2f32 * self.kp);
+        }
+
+        let q_dot = self.q * Quaternion::from_sv(0f32, gyro) * 0.5f32;
+        self.q = (self.q + q_dot * dt).normalize();
+    }
+
+    fn orientation(&self) -> Quaternion<f32> {
+        self.q
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Samples arrive at 100 Hz.
+    const DT: f32 = 0.01f32;
+
+    fn filters() -> Vec<Box<dyn Ahrs>> {
+        vec![
+            Box::new(Madgwick::new(0.1f32)),
+            Box::new(Mahony::new(0.5f32, 0f32)),
+        ]
+    }
+
+    // Direction of gravity in the sensor frame, as the filter estimates it.
+    fn gravity(filter: &dyn Ahrs) -> Vector3<f32> {
+        filter.orientation().conjugate() * vec3(0f32, 0f32, 1f32)
+    }
+
+    fn run(filter: &mut dyn Ahrs, gyro: Vector3<f32>, accel: Vector3<f32>, seconds: f32) {
+        for _ in 0..(seconds / DT) as usize {
+            filter.update(gyro, accel, None, DT);
+        }
+    }
+
+    #[test]
+    fn stationary_converges_to_gravity() {
+        let still = vec3(0f32, 0f32, 0f32);
+        // Tilted by 30° around x.
+        let (s, c) = 30f32.to_radians().sin_cos();
+        let tilted = vec3(0f32, s, c);
+        for mut filter in filters() {
+            run(filter.as_mut(), still, tilted * 9.81f32, 20f32);
+            assert!((gravity(filter.as_ref()) - tilted).magnitude() < 0.01f32);
+
+            // Back to level.
+            run(filter.as_mut(), still, vec3(0f32, 0f32, 9.81f32), 20f32);
+            let level = gravity(filter.as_ref());
+            assert!((level - vec3(0f32, 0f32, 1f32)).magnitude() < 0.01f32);
+        }
+    }
+
+    #[test]
+    fn constant_rate_integrates_yaw() {
+        let rate = 0.5f32; // [rad / s]
+        for mut filter in filters() {
+            run(
+                filter.as_mut(),
+                vec3(0f32, 0f32, rate),
+                vec3(0f32, 0f32, 9.81f32),
+                2f32,
+            );
+            let q = filter.orientation();
+            let yaw = 2f32 * q.v.z.atan2(q.s);
+            assert!((yaw - 2f32 * rate).abs() < 0.01f32, "yaw {}", yaw);
+            // Turning around gravity does not tilt.
+            assert!((gravity(filter.as_ref()) - vec3(0f32, 0f32, 1f32)).magnitude() < 0.001f32);
+        }
+    }
+}
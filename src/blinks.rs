@@ -0,0 +1,200 @@
+// Tells deliberate blinks and winks apart from natural blinking, using when
+// the eye tracker loses and finds each eye.
+//
+// A closure starts when either eye is lost and ends when both are found
+// again. If both eyes were lost at the same time, it was a blink: natural
+// blinks are short, so only blinks lasting between `long_min` and
+// `long_max` count. If only one eye was lost, it was a wink of that eye,
+// which counts if it lasted between `wink_min` and `wink_max`. Longer
+// closures are ignored, since the user most likely looked away.
+
+use std::time::Instant;
+
+use crate::config::{self, Eye};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blink {
+    Long,
+    WinkLeft,
+    WinkRight,
+}
+
+struct Closure {
+    start: Instant,
+    left: bool,
+    right: bool,
+    both: bool,
+}
+
+pub struct Detector {
+    config: config::Blinks,
+    eye: Eye,
+    closure: Option<Closure>,
+}
+
+impl Detector {
+    // With a single eye selected, the other one may well be invalid, so the
+    // selected eye stands in for both and only blinks are detected.
+    pub fn new(config: config::Blinks, eye: Eye) -> Detector {
+        Detector {
+            config,
+            eye,
+            closure: None,
+        }
+    }
+
+    // Whether an eye is closed at the moment.
+    pub fn is_closed(&self) -> bool {
+        self.closure.is_some()
+    }
+
+    // Takes whether the left and right eye are tracked as of `time`, and
+    // returns the deliberate blink that ended, if any.
+    pub fn update(&mut self, time: Instant, left: bool, right: bool) -> Option<Blink> {
+        if !self.config.enabled {
+            return None;
+        }
+        let (left, right) = match self.eye {
+            Eye::Both => (left, right),
+            Eye::Left => (left, left),
+            Eye::Right => (right, right),
+        };
+
+        if !left || !right {
+            let closure = self.closure.get_or_insert(Closure {
+                start: time,
+                left: false,
+                right: false,
+                both: false,
+            });
+            closure.left |= !left;
+            closure.right |= !right;
+            closure.both |= !left && !right;
+            return None;
+        }
+
+        let closure = self.closure.take()?;
+        let duration = time.saturating_duration_since(closure.start).as_secs_f32();
+        let within = |min: f32, max: f32| duration >= min && duration <= max;
+        if closure.both {
+            if within(self.config.long_min, self.config.long_max) {
+                return Some(Blink::Long);
+            }
+            return None;
+        }
+        if !within(self.config.wink_min, self.config.wink_max) {
+            return None;
+        }
+        // Both eyes closed in turn is neither.
+        match (closure.left, closure.right) {
+            (true, false) => Some(Blink::WinkLeft),
+            (false, true) => Some(Blink::WinkRight),
+            _ => None,
+        }
+    }
+
+    // Forgets the current closure, e.g. because the tracker was lost.
+    pub fn reset(&mut self) {
+        self.closure = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn detector(eye: Eye) -> Detector {
+        Detector::new(
+            config::Blinks {
+                enabled: true,
+                ..config::Blinks::default()
+            },
+            eye,
+        )
+    }
+
+    // Feeds spans of (duration [s], left tracked, right tracked) at 100 Hz,
+    // followed by both eyes open, and returns the blinks detected.
+    fn detect(eye: Eye, spans: &[(f32, bool, bool)]) -> Vec<Blink> {
+        let mut detector = detector(eye);
+        let start = Instant::now();
+        let samples = spans
+            .iter()
+            .chain(&[(0.1f32, true, true)])
+            .flat_map(|&(duration, left, right)| {
+                (0..(duration * 100f32).round() as usize).map(move |_| (left, right))
+            });
+        samples
+            .enumerate()
+            .filter_map(|(i, (left, right))| {
+                let time = start + Duration::from_millis(10 * i as u64);
+                detector.update(time, left, right)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deliberate_blink() {
+        let blink = [(0.5f32, true, true), (0.8f32, false, false)];
+        assert_eq!(detect(Eye::Both, &blink), vec![Blink::Long]);
+    }
+
+    #[test]
+    fn natural_blinks_are_ignored() {
+        let blinks = [
+            (0.5f32, true, true),
+            (0.15f32, false, false),
+            (2f32, true, true),
+            (0.3f32, false, false),
+        ];
+        assert!(detect(Eye::Both, &blinks).is_empty());
+    }
+
+    #[test]
+    fn winks() {
+        let left = [(0.5f32, true, true), (0.5f32, false, true)];
+        assert_eq!(detect(Eye::Both, &left), vec![Blink::WinkLeft]);
+        let right = [(0.5f32, true, true), (0.5f32, true, false)];
+        assert_eq!(detect(Eye::Both, &right), vec![Blink::WinkRight]);
+    }
+
+    #[test]
+    fn short_and_long_closures_are_ignored() {
+        let short = [(0.5f32, true, true), (0.1f32, false, true)];
+        assert!(detect(Eye::Both, &short).is_empty());
+        // Looking away.
+        let long = [(0.5f32, true, true), (3f32, false, false)];
+        assert!(detect(Eye::Both, &long).is_empty());
+    }
+
+    #[test]
+    fn eyes_closed_in_turn_are_ignored() {
+        let turns = [
+            (0.5f32, true, true),
+            (0.3f32, false, true),
+            (0.3f32, true, false),
+        ];
+        assert!(detect(Eye::Both, &turns).is_empty());
+    }
+
+    #[test]
+    fn closing_the_other_eye_makes_a_wink_a_blink() {
+        let closing = [
+            (0.5f32, true, true),
+            (0.2f32, false, true),
+            (0.6f32, false, false),
+        ];
+        assert_eq!(detect(Eye::Both, &closing), vec![Blink::Long]);
+    }
+
+    #[test]
+    fn single_eye_only_blinks() {
+        // The other eye is not followed, whatever the tracker says about it.
+        let blink = [(0.5f32, true, false), (0.8f32, false, false)];
+        assert_eq!(detect(Eye::Left, &blink), vec![Blink::Long]);
+        let wink = [(0.5f32, true, true), (0.8f32, true, false)];
+        assert_eq!(detect(Eye::Left, &wink), vec![]);
+    }
+}
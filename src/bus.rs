@@ -0,0 +1,360 @@
+// Carries events from the input sources to the pipeline.
+//
+// Sending never blocks, so device callbacks are not held up while the
+// pipeline is busy. Every source has its own bounded queue, which the
+// receiver serves in turn so that a high-rate source cannot starve the
+// others. Events are treated according to `Input::class`:
+//
+//  * Control events go to a separate unbounded queue, which is always
+//    served first and never drops anything.
+//  * Samples of which only the latest value matters replace a queued sample
+//    of the same kind (coalescing).
+//  * All other events are queued. If the queue of a source is full, its
+//    oldest event is dropped.
+
+use serde::Serialize;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::inputs::{Class, Event};
+
+// Number of events that are queued per source.
+const CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct QueueStats {
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+struct Queue {
+    name: &'static str,
+    events: VecDeque<Event>,
+    stats: QueueStats,
+}
+
+struct State {
+    control: VecDeque<Event>,
+    queues: Vec<Queue>,
+    // Queue to serve next.
+    next: usize,
+    senders: usize,
+    receiving: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+#[derive(Debug)]
+pub struct Disconnected;
+
+pub struct Sender {
+    shared: Arc<Shared>,
+    queue: usize,
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+pub fn new() -> Receiver {
+    Receiver {
+        shared: Arc::new(Shared {
+            state: Mutex::new(State {
+                control: VecDeque::new(),
+                queues: vec![],
+                next: 0,
+                senders: 0,
+                receiving: true,
+            }),
+            available: Condvar::new(),
+        }),
+    }
+}
+
+fn add_queue(shared: &Arc<Shared>, name: &'static str) -> Sender {
+    let mut state = shared.state.lock().unwrap();
+    state.queues.push(Queue {
+        name,
+        events: VecDeque::with_capacity(CAPACITY),
+        stats: QueueStats::default(),
+    });
+    state.senders += 1;
+    Sender {
+        shared: shared.clone(),
+        queue: state.queues.len() - 1,
+    }
+}
+
+impl Receiver {
+    // Creates a sender with its own queue.
+    pub fn sender(&self, name: &'static str) -> Sender {
+        add_queue(&self.shared, name)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(event) = state.pop() {
+                return Ok(event);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = self
+                .shared
+                .available
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    // Drop and coalesce counters of every source.
+    pub fn stats(&self) -> BTreeMap<&'static str, QueueStats> {
+        let state = self.shared.state.lock().unwrap();
+        let mut stats = BTreeMap::new();
+        for queue in &state.queues {
+            let total = stats.entry(queue.name).or_insert_with(QueueStats::default);
+            total.dropped += queue.stats.dropped;
+            total.coalesced += queue.stats.coalesced;
+        }
+        stats
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiving = false;
+    }
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Event> {
+        if let Some(event) = self.control.pop_front() {
+            return Some(event);
+        }
+        let count = self.queues.len();
+        for i in 0..count {
+            let index = (self.next + i) % count;
+            if let Some(event) = self.queues[index].events.pop_front() {
+                self.next = (index + 1) % count;
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+impl Sender {
+    // Creates another sender on the same bus, with its own queue.
+    pub fn sender(&self, name: &'static str) -> Sender {
+        add_queue(&self.shared, name)
+    }
+
+    pub fn send(&self, event: Event) -> Result<(), Disconnected> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.receiving {
+            return Err(Disconnected);
+        }
+
+        match event.input.class() {
+            Class::Control => state.control.push_back(event),
+            Class::Latest => {
+                let queue = &mut state.queues[self.queue];
+                let kind = mem::discriminant(&event.input);
+                match queue
+                    .events
+                    .iter_mut()
+                    .find(|queued| mem::discriminant(&queued.input) == kind)
+                {
+                    Some(queued) => {
+                        *queued = event;
+                        queue.stats.coalesced += 1;
+                    }
+                    None => queue.push(event),
+                }
+            }
+            Class::Queued => state.queues[self.queue].push(event),
+        }
+
+        self.shared.available.notify_one();
+        Ok(())
+    }
+}
+
+impl Queue {
+    fn push(&mut self, event: Event) {
+        if self.events.len() == CAPACITY {
+            self.events.pop_front();
+            self.stats.dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+}
+
+// Clones share the queue of the original.
+impl Clone for Sender {
+    fn clone(&self) -> Sender {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+            queue: self.queue,
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::inputs::Input;
+
+    fn gaze(x: f32) -> Event {
+        Event::now(Input::TobiiGaze { x, y: 0f32 })
+    }
+
+    fn gyro(x: f32) -> Event {
+        Event::now(Input::Gyro {
+            x,
+            y: 0f32,
+            z: 0f32,
+        })
+    }
+
+    // Receives everything that is queued.
+    fn drain(rx: &Receiver) -> Vec<Input> {
+        let mut inputs = vec![];
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(0)) {
+            inputs.push(event.input);
+        }
+        inputs
+    }
+
+    fn gyro_x(input: &Input) -> f32 {
+        match *input {
+            Input::Gyro { x, .. } => x,
+            _ => panic!("expected angular velocity"),
+        }
+    }
+
+    #[test]
+    fn coalesces_latest_samples_of_a_kind() {
+        let rx = new();
+        let tx = rx.sender("tobii");
+        tx.send(gaze(1f32)).unwrap();
+        tx.send(Event::now(Input::TobiiPresence { present: true }))
+            .unwrap();
+        tx.send(gaze(2f32)).unwrap();
+        tx.send(gyro(1f32)).unwrap();
+        tx.send(gaze(3f32)).unwrap();
+
+        let inputs = drain(&rx);
+        assert_eq!(inputs.len(), 3);
+        assert!(matches!(inputs[0], Input::TobiiPresence { present: true }));
+        // Takes the place of the first sample.
+        assert!(matches!(inputs[1], Input::TobiiGaze { x, .. } if x == 3f32));
+        assert_eq!(gyro_x(&inputs[2]), 1f32);
+        assert_eq!(rx.stats()["tobii"].coalesced, 2);
+        assert_eq!(rx.stats()["tobii"].dropped, 0);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let rx = new();
+        let tx = rx.sender("gyro");
+        for i in 0..CAPACITY + 5 {
+            tx.send(gyro(i as f32)).unwrap();
+        }
+
+        let received: Vec<f32> = drain(&rx).iter().map(gyro_x).collect();
+        let expected: Vec<f32> = (5..CAPACITY + 5).map(|i| i as f32).collect();
+        assert_eq!(received, expected);
+        assert_eq!(rx.stats()["gyro"].dropped, 5);
+    }
+
+    #[test]
+    fn serves_control_first() {
+        let rx = new();
+        let samples = rx.sender("gyro");
+        let control = rx.sender("config");
+        for i in 0..3 {
+            samples.send(gyro(i as f32)).unwrap();
+        }
+        control.send(Event::now(Input::Recenter)).unwrap();
+        // Control inputs are never dropped, however many there are.
+        for _ in 0..CAPACITY {
+            samples.send(Event::now(Input::Recenter)).unwrap();
+        }
+
+        let inputs = drain(&rx);
+        assert_eq!(inputs.len(), CAPACITY + 4);
+        assert!(inputs[..=CAPACITY]
+            .iter()
+            .all(|input| matches!(input, Input::Recenter)));
+        assert_eq!(inputs[CAPACITY + 1..].iter().map(gyro_x).count(), 3);
+    }
+
+    #[test]
+    fn serves_sources_in_turn() {
+        let rx = new();
+        let gyro_tx = rx.sender("gyro");
+        let tobii_tx = rx.sender("tobii");
+        for i in 0..4 {
+            gyro_tx.send(gyro(i as f32)).unwrap();
+        }
+        tobii_tx
+            .send(Event::now(Input::TobiiEyeValidity {
+                left: true,
+                right: true,
+            }))
+            .unwrap();
+        tobii_tx.send(gaze(1f32)).unwrap();
+
+        let inputs = drain(&rx);
+        assert_eq!(inputs.len(), 6);
+        assert_eq!(gyro_x(&inputs[0]), 0f32);
+        assert!(matches!(inputs[1], Input::TobiiEyeValidity { .. }));
+        assert_eq!(gyro_x(&inputs[2]), 1f32);
+        assert!(matches!(inputs[3], Input::TobiiGaze { .. }));
+        // Once the other queue is empty, the busy one is served alone.
+        assert_eq!(gyro_x(&inputs[4]), 2f32);
+        assert_eq!(gyro_x(&inputs[5]), 3f32);
+    }
+
+    #[test]
+    fn disconnects_without_senders() {
+        let rx = new();
+        let tx = rx.sender("gyro");
+        tx.send(gyro(1f32)).unwrap();
+        drop(tx);
+        // Queued events are still delivered.
+        assert!(rx.recv_timeout(Duration::from_millis(0)).is_ok());
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_millis(0)),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+
+        let tx = rx.sender("gyro");
+        drop(rx);
+        assert!(tx.send(gyro(1f32)).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+// Maps timestamps of device clocks to the monotonic clock of the host.
+//
+// Every observation pairs a device timestamp with the instant it was seen on
+// the host. Their difference is the offset between both clocks plus the
+// delay of that particular observation, so the smallest difference within a
+// recent window is the best estimate of the offset. Older observations are
+// forgotten so that drift between the clocks is followed.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// How long observations are taken into account.
+const WINDOW: Duration = Duration::from_secs(60);
+
+pub struct Clock {
+    epoch: Instant,
+    // Host instant of the observation and offset [µs] of the host clock
+    // (relative to `epoch`) against the device clock.
+    offsets: VecDeque<(Instant, i64)>,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            epoch: Instant::now(),
+            offsets: VecDeque::new(),
+        }
+    }
+
+    fn micros(&self, instant: Instant) -> i64 {
+        if instant >= self.epoch {
+            instant.duration_since(self.epoch).as_micros() as i64
+        } else {
+            -(self.epoch.duration_since(instant).as_micros() as i64)
+        }
+    }
+
+    fn instant(&self, micros: i64) -> Instant {
+        if micros >= 0 {
+            self.epoch + Duration::from_micros(micros as u64)
+        } else {
+            self.epoch
+                .checked_sub(Duration::from_micros(-micros as u64))
+                .unwrap_or(self.epoch)
+        }
+    }
+
+    // Records that device time `device_us` was current at `host`.
+    pub fn observe(&mut self, device_us: i64, host: Instant) {
+        while let Some(&(at, _)) = self.offsets.front() {
+            if host.saturating_duration_since(at) <= WINDOW {
+                break;
+            }
+            self.offsets.pop_front();
+        }
+        self.offsets.push_back((host, self.micros(host) - device_us));
+    }
+
+    // Host instant of device time `device_us`, once anything was observed.
+    pub fn to_host(&self, device_us: i64) -> Option<Instant> {
+        let offset = self.offsets.iter().map(|&(_, offset)| offset).min()?;
+        Some(self.instant(device_us + offset))
+    }
+
+    // Forgets all observations, e.g. because the device clock was reset.
+    pub fn reset(&mut self) {
+        self.offsets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn takes_the_smallest_offset() {
+        let start = Instant::now();
+        let mut clock = Clock::new();
+        clock.epoch = start;
+        assert!(clock.to_host(0).is_none());
+
+        // Seen 5 ms, then 2 ms after the device time.
+        clock.observe(0, start + ms(5));
+        clock.observe(10_000, start + ms(12));
+        assert_eq!(clock.to_host(20_000), Some(start + ms(22)));
+        // A later, slower observation does not change the estimate.
+        clock.observe(30_000, start + ms(40));
+        assert_eq!(clock.to_host(20_000), Some(start + ms(22)));
+    }
+
+    #[test]
+    fn forgets_old_observations() {
+        let start = Instant::now();
+        let mut clock = Clock::new();
+        clock.epoch = start;
+
+        // One observation without delay, then all of them 5 ms late.
+        clock.observe(0, start);
+        clock.observe(30_000_000, start + ms(30_005));
+        clock.observe(59_000_000, start + ms(59_005));
+        assert_eq!(clock.to_host(0), Some(start));
+        clock.observe(61_000_000, start + ms(61_005));
+        assert_eq!(clock.to_host(0), Some(start + ms(5)));
+
+        clock.reset();
+        assert!(clock.to_host(0).is_none());
+    }
+}
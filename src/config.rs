@@ -0,0 +1,1011 @@
+// Configuration is read from a TOML file at startup. Every field has a
+// default, so an empty or missing file is a valid configuration. Values are
+// checked on load, and the file is watched for changes (see `reload`).
+//
+// The file is looked up at (first match wins):
+//  * $GLIMPSE_CONFIG
+//  * $XDG_CONFIG_HOME/glimpse/config.toml
+//  * $HOME/.config/glimpse/config.toml
+
+use serde::{Deserialize, Serialize};
+
+use crate::acceleration::Curve;
+use crate::blinks::Blink;
+use crate::gestures::{Action, Gesture};
+use crate::output::Backend;
+use crate::presence::AbsenceAction;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    // A value is out of its range.
+    Invalid(String),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub pointing: Pointing,
+    pub gyro: Gyro,
+    pub output: Output,
+    pub presence: Presence,
+    pub tobii: Tobii,
+    pub dwell: Dwell,
+    pub gestures: Gestures,
+    pub blinks: Blinks,
+    pub gaze_scroll: GazeScroll,
+    pub snapping: Snapping,
+    pub zoom: Zoom,
+    pub screens: Screens,
+    pub screen_switch: ScreenSwitch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PointingMode {
+    // Gaze warps the cursor, head angular velocity refines it.
+    Relative,
+    // Head orientation alone determines the cursor position.
+    Absolute,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Pointing {
+    pub mode: PointingMode,
+    pub relative: RelativePointing,
+    pub absolute: AbsolutePointing,
+}
+
+impl Default for Pointing {
+    fn default() -> Self {
+        Pointing {
+            mode: PointingMode::Relative,
+            relative: RelativePointing::default(),
+            absolute: AbsolutePointing::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RelativePointing {
+    // Gain of horizontal head movement over vertical, since the head turns
+    // more easily than it nods.
+    pub horizontal_gain: f32,
+    // Head movement [px] per sample above which the head counts as moving.
+    // Gaze does not move the cursor while the head moves.
+    pub head_move: f32,
+    // Distance [px] between gaze and cursor within which gaze is ignored.
+    pub dead_zone: f32,
+    // Distance [px] between gaze and cursor from which the cursor jumps to
+    // the gaze.
+    pub jump_distance: f32,
+    // Once the head has rested for `settle_time` [s], the cursor jumps to
+    // gaze farther than `settle_distance` [px] away, and moves halfway to
+    // gaze closer than that.
+    pub settle_distance: f32,
+    pub settle_time: f32,
+    // Time [s] the head must rest before gaze moves the cursor. It is
+    // shortened by up to `jump_delay_reduction` [s] for gaze far away from
+    // the cursor, at a rate of `jump_delay_gain` [s] per ratio of the
+    // distance to the diagonal of the screen.
+    pub jump_delay: f32,
+    pub jump_delay_reduction: f32,
+    pub jump_delay_gain: f32,
+    // Transfer function from head speed to gain.
+    pub acceleration: Curve,
+}
+
+impl Default for RelativePointing {
+    fn default() -> Self {
+        RelativePointing {
+            horizontal_gain: 1.7,
+            head_move: 2.0,
+            dead_zone: 5.0,
+            jump_distance: 30.0,
+            settle_distance: 20.0,
+            settle_time: 1.0,
+            jump_delay: 1.2,
+            jump_delay_reduction: 0.6,
+            jump_delay_gain: 24.0,
+            acceleration: Curve::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadSource {
+    // Angle frames computed on board of the IMU (0x53).
+    Angle,
+    // Orientation fused in-process from the raw IMU streams.
+    Fused,
+    // Head pose reported by the eye tracker.
+    Tobii,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AbsolutePointing {
+    pub source: HeadSource,
+
+    // Angle [°] the head has to turn away from the neutral pose for the
+    // cursor to reach the respective edge of the screen.
+    pub left: f32,
+    pub right: f32,
+    pub up: f32,
+    pub down: f32,
+
+    // Exponent of the gain curve applied to the normalized deflection.
+    // Values above 1 give finer control around the neutral pose at the
+    // cost of coarser control towards the edges.
+    pub exponent: f32,
+
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Default for AbsolutePointing {
+    fn default() -> Self {
+        AbsolutePointing {
+            source: HeadSource::Fused,
+            left: 20f32,
+            right: 20f32,
+            up: 12f32,
+            down: 12f32,
+            exponent: 1.5f32,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Output {
+    pub backend: Backend,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            backend: Backend::Xdotool,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Tobii {
+    // URL, serial number or model of the eye tracker to use. If not set, the
+    // first one found is used.
+    pub device: Option<String>,
+    // Keep looking for the eye tracker until it is connected, instead of
+    // giving up.
+    pub wait_for_device: bool,
+    // Eye(s) the gaze is taken from.
+    pub eye: Eye,
+}
+
+impl Default for Tobii {
+    fn default() -> Self {
+        Tobii {
+            device: None,
+            wait_for_device: true,
+            eye: Eye::Both,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eye {
+    // Gaze combined from both eyes.
+    Both,
+    // Gaze of one eye only, for users whose other eye deviates or is hard
+    // to track (e.g. because of strabismus or amblyopia).
+    Left,
+    Right,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Presence {
+    // Stop moving the cursor while nobody is in front of the eye tracker.
+    pub enabled: bool,
+    // Put the cursor back where it was when the user left.
+    pub restore_cursor: bool,
+    pub absence_action: AbsenceAction,
+    // Time [s] of absence after which `absence_action` is run.
+    pub absence_timeout: f32,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Presence {
+            enabled: true,
+            restore_cursor: true,
+            absence_action: AbsenceAction::None,
+            absence_timeout: 300f32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Dwell {
+    // Click by holding the cursor still.
+    pub enabled: bool,
+    // Time [s] the cursor has to rest before clicking.
+    pub time: f32,
+    // Distance [px] the cursor may drift while resting.
+    pub radius: f32,
+    // Time [s] after a click during which no dwell starts.
+    pub cooldown: f32,
+    // Show the progress of a dwell next to the cursor.
+    pub indicator: bool,
+    // Show buttons for choosing the click type at the right edge of the
+    // screen.
+    pub palette: bool,
+}
+
+impl Default for Dwell {
+    fn default() -> Self {
+        Dwell {
+            enabled: false,
+            time: 0.8f32,
+            radius: 20f32,
+            cooldown: 1f32,
+            indicator: true,
+            palette: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Zoom {
+    // Allow magnifying the area around the gaze with the `zoom` action.
+    pub enabled: bool,
+    // Magnification.
+    pub factor: u16,
+    // Width and height [px] of the magnified picture.
+    pub size: [u16; 2],
+}
+
+impl Default for Zoom {
+    fn default() -> Self {
+        Zoom {
+            enabled: false,
+            factor: 3,
+            size: [600, 400],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Screens {
+    // Name of the monitor the eye tracker is mounted on, as listed by
+    // `glimpse screens`. The primary monitor if not given.
+    pub tracker: Option<String>,
+    // Width and height [px] of the screen if the monitors cannot be read.
+    pub fallback: [u32; 2],
+}
+
+impl Default for Screens {
+    fn default() -> Self {
+        Screens {
+            tracker: None,
+            fallback: [2560, 1440],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScreenSwitch {
+    // Point with the head alone on monitors without an eye tracker, when the
+    // head turns towards them.
+    pub enabled: bool,
+    // Head rotation [°] per pixel between the centers of two monitors, for
+    // estimating the directions of monitors not in `directions`.
+    pub degrees_per_px: f32,
+    // How much closer [°] the head must point to another monitor to switch.
+    pub hysteresis: f32,
+    // Direction [°] (yaw, pitch) of monitors by name, relative to the monitor
+    // with the eye tracker. Turning right and looking up are positive.
+    pub directions: BTreeMap<String, [f32; 2]>,
+}
+
+impl Default for ScreenSwitch {
+    fn default() -> Self {
+        ScreenSwitch {
+            enabled: false,
+            degrees_per_px: 0.02,
+            hysteresis: 10.0,
+            directions: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Snapping {
+    // Move the cursor onto clickable elements near the gaze, as found in the
+    // accessibility tree.
+    pub enabled: bool,
+    // Distance [px] from the gaze within which elements are snapped to.
+    pub radius: f32,
+    // Time [s] between lookups of the elements on screen.
+    pub refresh: f32,
+}
+
+impl Default for Snapping {
+    fn default() -> Self {
+        Snapping {
+            enabled: false,
+            radius: 40f32,
+            refresh: 1f32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GazeScroll {
+    // Scroll when looking near the edge of a window from the start. Can be
+    // toggled with the `gaze_scroll` action either way.
+    pub enabled: bool,
+    // Size of the zone at each edge, as a fraction of the window size.
+    pub zone: f32,
+    // How far [fraction of the zone] the gaze has to leave the zone to stop
+    // scrolling.
+    pub hysteresis: f32,
+    // Time [s] the gaze has to be in a zone to start scrolling.
+    pub delay: f32,
+    // Steps [1/s] scrolled at the very edge.
+    pub speed: f32,
+    // Also scroll at the left and right edges.
+    pub horizontal: bool,
+}
+
+impl Default for GazeScroll {
+    fn default() -> Self {
+        GazeScroll {
+            enabled: false,
+            zone: 0.15f32,
+            hysteresis: 0.2f32,
+            delay: 0.3f32,
+            speed: 10f32,
+            horizontal: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Gestures {
+    // Recognize head gestures in the motion reported by the IMU.
+    pub enabled: bool,
+    // Angular velocity [°/s] above which the head is swinging. Slower motion
+    // is pointing.
+    pub speed: f32,
+    // Number of alternating swings that make a nod or shake.
+    pub swings: usize,
+    // Time [s] within which the next swing has to start.
+    pub gap: f32,
+    // Time [s] within which a gesture has to be complete.
+    pub window: f32,
+    // Angular velocity [°/s] and duration [s] of a flick.
+    pub flick_speed: f32,
+    pub flick_time: f32,
+    // Time [s] after a gesture during which motion is ignored.
+    pub cooldown: f32,
+    // Rotation [°] per step of the scroll wheel in scroll mode.
+    pub scroll_angle: f32,
+    pub actions: GestureActions,
+}
+
+impl Default for Gestures {
+    fn default() -> Self {
+        Gestures {
+            enabled: false,
+            speed: 60f32,
+            swings: 3,
+            gap: 0.25f32,
+            window: 1.2f32,
+            flick_speed: 200f32,
+            flick_time: 0.15f32,
+            cooldown: 0.5f32,
+            scroll_angle: 3f32,
+            actions: GestureActions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GestureActions {
+    pub nod: Action,
+    pub shake: Action,
+    pub tilt_left: Action,
+    pub tilt_right: Action,
+    pub flick_left: Action,
+    pub flick_right: Action,
+    pub flick_up: Action,
+    pub flick_down: Action,
+}
+
+impl Default for GestureActions {
+    fn default() -> Self {
+        GestureActions {
+            nod: Action::LeftClick,
+            shake: Action::Toggle,
+            tilt_left: Action::None,
+            tilt_right: Action::RightClick,
+            flick_left: Action::None,
+            flick_right: Action::None,
+            flick_up: Action::None,
+            flick_down: Action::ScrollMode,
+        }
+    }
+}
+
+impl Gestures {
+    // Whether some gesture runs `action`.
+    pub fn triggers(&self, action: Action) -> bool {
+        let gestures = [
+            Gesture::Nod,
+            Gesture::Shake,
+            Gesture::TiltLeft,
+            Gesture::TiltRight,
+            Gesture::FlickLeft,
+            Gesture::FlickRight,
+            Gesture::FlickUp,
+            Gesture::FlickDown,
+        ];
+        self.enabled && gestures.iter().any(|&g| self.actions.get(g) == action)
+    }
+}
+
+impl GestureActions {
+    pub fn get(&self, gesture: Gesture) -> Action {
+        match gesture {
+            Gesture::Nod => self.nod,
+            Gesture::Shake => self.shake,
+            Gesture::TiltLeft => self.tilt_left,
+            Gesture::TiltRight => self.tilt_right,
+            Gesture::FlickLeft => self.flick_left,
+            Gesture::FlickRight => self.flick_right,
+            Gesture::FlickUp => self.flick_up,
+            Gesture::FlickDown => self.flick_down,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Blinks {
+    // Click by blinking or winking deliberately.
+    pub enabled: bool,
+    // Duration [s] of a deliberate blink. Natural blinks are shorter.
+    pub long_min: f32,
+    pub long_max: f32,
+    // Duration [s] of a wink.
+    pub wink_min: f32,
+    pub wink_max: f32,
+    pub actions: BlinkActions,
+}
+
+impl Default for Blinks {
+    fn default() -> Self {
+        Blinks {
+            enabled: false,
+            long_min: 0.5f32,
+            long_max: 2f32,
+            wink_min: 0.25f32,
+            wink_max: 2f32,
+            actions: BlinkActions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BlinkActions {
+    pub long: Action,
+    pub wink_left: Action,
+    pub wink_right: Action,
+}
+
+impl Default for BlinkActions {
+    fn default() -> Self {
+        BlinkActions {
+            long: Action::LeftClick,
+            wink_left: Action::LeftClick,
+            wink_right: Action::RightClick,
+        }
+    }
+}
+
+impl Blinks {
+    // Whether some blink runs `action`.
+    pub fn triggers(&self, action: Action) -> bool {
+        let blinks = [Blink::Long, Blink::WinkLeft, Blink::WinkRight];
+        self.enabled && blinks.iter().any(|&b| self.actions.get(b) == action)
+    }
+}
+
+impl BlinkActions {
+    pub fn get(&self, blink: Blink) -> Action {
+        match blink {
+            Blink::Long => self.long,
+            Blink::WinkLeft => self.wink_left,
+            Blink::WinkRight => self.wink_right,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Gyro {
+    // Rotation from the frame of the IMU to the frame of the head, as rows.
+    // The rows are the yaw (turning right), pitch (looking down) and roll
+    // axes of the head, expressed in the frame of the IMU. Estimate it with
+    // `glimpse gyro calibrate-mount`.
+    pub mount: [[f32; 3]; 3],
+    // Serial port the IMU is connected to.
+    pub tty: String,
+    // The IMU defaults to 9600, but should be configured as fast as
+    // possible.
+    pub baud_rate: u32,
+    // Filter fusing the measurements of the IMU into its orientation.
+    pub filter: Filter,
+    // Gain of the Madgwick filter. Values around 0.1 are a good compromise
+    // between convergence speed and noise for consumer grade MEMS.
+    pub beta: f32,
+    // Proportional and integral gains of the Mahony filter. The integral
+    // term cancels gyroscope bias, but slows down convergence.
+    pub kp: f32,
+    pub ki: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Madgwick,
+    Mahony,
+}
+
+#[cfg(unix)]
+const DEFAULT_TTY: &str = "/dev/ttyUSB0";
+#[cfg(windows)]
+const DEFAULT_TTY: &str = "COM1";
+
+impl Default for Gyro {
+    fn default() -> Self {
+        // The author's sensor: yaw around x, pitch around z, and rotated by
+        // 13° within that plane.
+        let (s, c) = 13f32.to_radians().sin_cos();
+        Gyro {
+            mount: [[c, 0f32, -s], [s, 0f32, c], [0f32, -1f32, 0f32]],
+            tty: DEFAULT_TTY.to_string(),
+            baud_rate: 921600,
+            filter: Filter::Madgwick,
+            beta: 0.1f32,
+            kp: 0.5f32,
+            ki: 0f32,
+        }
+    }
+}
+
+fn valid_curve(curve: &Curve) -> bool {
+    match *curve {
+        Curve::Linear { gain } => gain > 0f32,
+        Curve::Power { gain, exponent } => gain > 0f32 && exponent > 0f32,
+        Curve::Sigmoid {
+            min_gain,
+            max_gain,
+            steepness,
+            ..
+        } => min_gain > 0f32 && max_gain > 0f32 && steepness > 0f32,
+        Curve::Adaptive {
+            threshold,
+            incline,
+            max_gain,
+        } => threshold >= 0f32 && incline >= 0f32 && max_gain > 0f32,
+        Curve::Piecewise { ref points } => {
+            points.iter().all(|&[speed, gain]| speed >= 0f32 && gain >= 0f32)
+                && points.windows(2).all(|pair| pair[0][0] <= pair[1][0])
+        }
+    }
+}
+
+// Whether the rows of `m` are orthonormal and keep handedness.
+fn is_rotation(m: &[[f32; 3]; 3]) -> bool {
+    const TOLERANCE: f32 = 1e-3;
+    let dot = |a: &[f32; 3], b: &[f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let orthonormal = (0..3).all(|i| {
+        (0..3).all(|j| {
+            let expected = if i == j { 1f32 } else { 0f32 };
+            (dot(&m[i], &m[j]) - expected).abs() < TOLERANCE
+        })
+    });
+    let cross = [
+        m[1][1] * m[2][2] - m[1][2] * m[2][1],
+        m[1][2] * m[2][0] - m[1][0] * m[2][2],
+        m[1][0] * m[2][1] - m[1][1] * m[2][0],
+    ];
+    orthonormal && (dot(&m[0], &cross) - 1f32).abs() < TOLERANCE
+}
+
+pub fn path() -> PathBuf {
+    if let Some(path) = env::var_os("GLIMPSE_CONFIG") {
+        return PathBuf::from(path);
+    }
+
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+
+    base.join("glimpse").join("config.toml")
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = path();
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(&path)?;
+        let config: Config = toml::from_str(&contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks values that would make no sense, or make components panic.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        let relative = &self.pointing.relative;
+        check(
+            relative.horizontal_gain > 0f32,
+            "pointing.relative.horizontal_gain must be positive",
+        );
+        check(
+            relative.head_move >= 0f32,
+            "pointing.relative.head_move must not be negative",
+        );
+        check(
+            relative.dead_zone >= 0f32,
+            "pointing.relative.dead_zone must not be negative",
+        );
+        check(
+            relative.jump_distance >= 0f32,
+            "pointing.relative.jump_distance must not be negative",
+        );
+        check(
+            relative.settle_distance <= relative.jump_distance,
+            "pointing.relative.settle_distance must not exceed jump_distance",
+        );
+        check(
+            relative.settle_time >= 0f32,
+            "pointing.relative.settle_time must not be negative",
+        );
+        check(
+            relative.jump_delay > 0f32,
+            "pointing.relative.jump_delay must be positive",
+        );
+        check(
+            relative.jump_delay_reduction >= 0f32
+                && relative.jump_delay_reduction <= relative.jump_delay,
+            "pointing.relative.jump_delay_reduction must be between 0 and jump_delay",
+        );
+        check(
+            relative.jump_delay_gain >= 0f32,
+            "pointing.relative.jump_delay_gain must not be negative",
+        );
+        check(
+            valid_curve(&relative.acceleration),
+            "pointing.relative.acceleration must have positive gains and exponent, \
+             and piecewise points sorted by speed and not negative",
+        );
+
+        let absolute = &self.pointing.absolute;
+        check(
+            [absolute.left, absolute.right, absolute.up, absolute.down]
+                .iter()
+                .all(|&angle| angle > 0f32),
+            "pointing.absolute angles must be positive",
+        );
+        check(
+            absolute.exponent > 0f32,
+            "pointing.absolute.exponent must be positive",
+        );
+
+        check(
+            self.presence.absence_timeout > 0f32,
+            "presence.absence_timeout must be positive",
+        );
+
+        check(self.dwell.time > 0f32, "dwell.time must be positive");
+        check(
+            self.dwell.radius >= 0f32,
+            "dwell.radius must not be negative",
+        );
+        check(
+            self.dwell.cooldown >= 0f32,
+            "dwell.cooldown must not be negative",
+        );
+
+        let gestures = &self.gestures;
+        check(
+            gestures.speed > 0f32 && gestures.flick_speed > 0f32,
+            "gestures.speed and gestures.flick_speed must be positive",
+        );
+        check(gestures.swings > 0, "gestures.swings must be positive");
+        check(
+            gestures.gap >= 0f32
+                && gestures.window > 0f32
+                && gestures.flick_time > 0f32
+                && gestures.cooldown >= 0f32,
+            "gestures times must not be negative",
+        );
+        check(
+            gestures.scroll_angle > 0f32,
+            "gestures.scroll_angle must be positive",
+        );
+
+        let blinks = &self.blinks;
+        check(
+            blinks.long_min >= 0f32 && blinks.long_min <= blinks.long_max,
+            "blinks.long_min must be between 0 and long_max",
+        );
+        check(
+            blinks.wink_min >= 0f32 && blinks.wink_min <= blinks.wink_max,
+            "blinks.wink_min must be between 0 and wink_max",
+        );
+
+        let scroll = &self.gaze_scroll;
+        check(
+            scroll.zone > 0f32 && scroll.zone <= 0.5f32,
+            "gaze_scroll.zone must be above 0 and at most 0.5",
+        );
+        check(
+            scroll.hysteresis >= 0f32 && scroll.delay >= 0f32 && scroll.speed >= 0f32,
+            "gaze_scroll.hysteresis, delay and speed must not be negative",
+        );
+
+        check(
+            self.snapping.radius >= 0f32,
+            "snapping.radius must not be negative",
+        );
+        check(
+            self.snapping.refresh > 0f32,
+            "snapping.refresh must be positive",
+        );
+        check(self.zoom.factor >= 1, "zoom.factor must be at least 1");
+        check(
+            self.zoom.size.iter().all(|&size| size > 0),
+            "zoom.size must be positive",
+        );
+        check(
+            self.screens.fallback.iter().all(|&size| size > 0),
+            "screens.fallback must be positive",
+        );
+        check(
+            self.screen_switch.degrees_per_px >= 0f32 && self.screen_switch.hysteresis >= 0f32,
+            "screen_switch.degrees_per_px and hysteresis must not be negative",
+        );
+        check(self.gyro.baud_rate > 0, "gyro.baud_rate must be positive");
+        check(
+            is_rotation(&self.gyro.mount),
+            "gyro.mount must be a rotation (orthonormal rows, determinant 1)",
+        );
+        check(
+            self.gyro.beta > 0f32 && self.gyro.kp > 0f32,
+            "gyro.beta and gyro.kp must be positive",
+        );
+        check(self.gyro.ki >= 0f32, "gyro.ki must not be negative");
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors.join("; ")))
+        }
+    }
+
+    // Sections that differ from `other`, but are only applied on startup,
+    // since they set up devices, windows or threads.
+    pub fn restart_needed(&self, other: &Config) -> Vec<&'static str> {
+        fn differ<T: Serialize>(a: &T, b: &T) -> bool {
+            toml::Value::try_from(a).ok() != toml::Value::try_from(b).ok()
+        }
+        let sections = [
+            (
+                "pointing.absolute.source",
+                self.pointing.absolute.source != other.pointing.absolute.source,
+            ),
+            ("gyro", differ(&self.gyro, &other.gyro)),
+            ("output", differ(&self.output, &other.output)),
+            ("presence", differ(&self.presence, &other.presence)),
+            ("tobii", differ(&self.tobii, &other.tobii)),
+            ("dwell", differ(&self.dwell, &other.dwell)),
+            ("gaze_scroll", differ(&self.gaze_scroll, &other.gaze_scroll)),
+            ("snapping", differ(&self.snapping, &other.snapping)),
+            ("zoom", differ(&self.zoom, &other.zoom)),
+            ("screens", differ(&self.screens, &other.screens)),
+            (
+                "screen_switch",
+                differ(&self.screen_switch, &other.screen_switch),
+            ),
+        ];
+        sections
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(change: impl FnOnce(&mut Config)) -> bool {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().is_err()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+        let empty: Config = toml::from_str("").unwrap();
+        empty.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_curves() {
+        let with = |curve: Curve| invalid(|c| c.pointing.relative.acceleration = curve);
+        assert!(with(Curve::Linear { gain: 0f32 }));
+        assert!(with(Curve::Power {
+            gain: 1f32,
+            exponent: -1f32
+        }));
+        assert!(with(Curve::Sigmoid {
+            min_gain: 0f32,
+            max_gain: 2f32,
+            midpoint: 5f32,
+            steepness: 1f32,
+        }));
+        assert!(with(Curve::Adaptive {
+            threshold: 5f32,
+            incline: 0.1f32,
+            max_gain: 0f32,
+        }));
+        assert!(with(Curve::Piecewise {
+            points: vec![[10f32, 1f32], [5f32, 2f32]],
+        }));
+        assert!(with(Curve::Piecewise {
+            points: vec![[-1f32, 1f32], [5f32, 2f32]],
+        }));
+        assert!(with(Curve::Piecewise {
+            points: vec![[0f32, -1f32], [5f32, 2f32]],
+        }));
+        assert!(!with(Curve::Piecewise {
+            points: vec![[0f32, 0.5f32], [5f32, 1f32], [5f32, 2f32]],
+        }));
+        assert!(!with(Curve::Power {
+            gain: 1f32,
+            exponent: 1.5f32
+        }));
+    }
+
+    #[test]
+    fn rejects_bad_jumps() {
+        assert!(invalid(|c| {
+            c.pointing.relative.jump_distance = -1f32;
+            c.pointing.relative.settle_distance = -2f32;
+        }));
+        assert!(invalid(|c| {
+            c.pointing.relative.jump_delay = 0f32;
+            c.pointing.relative.jump_delay_reduction = 0f32;
+        }));
+    }
+
+    #[test]
+    fn rejects_zero_absence_timeout() {
+        assert!(invalid(|c| c.presence.absence_timeout = 0f32));
+    }
+
+    #[test]
+    fn absence_timeout_in_seconds() {
+        let config: Config = toml::from_str("[presence]\nabsence_timeout = 120").unwrap();
+        assert_eq!(config.presence.absence_timeout, 120f32);
+        let config: Config = toml::from_str("[presence]\nabsence_timeout = 2.5").unwrap();
+        assert_eq!(config.presence.absence_timeout, 2.5f32);
+    }
+
+    #[test]
+    fn rejects_mounts_that_are_not_rotations() {
+        let identity = [[1f32, 0f32, 0f32], [0f32, 1f32, 0f32], [0f32, 0f32, 1f32]];
+        assert!(!invalid(|c| c.gyro.mount = identity));
+        // Mirrored.
+        assert!(invalid(|c| {
+            c.gyro.mount = [[-1f32, 0f32, 0f32], [0f32, 1f32, 0f32], [0f32, 0f32, 1f32]]
+        }));
+        // Scaled.
+        assert!(invalid(|c| {
+            c.gyro.mount = [[2f32, 0f32, 0f32], [0f32, 1f32, 0f32], [0f32, 0f32, 1f32]]
+        }));
+        // Not orthogonal.
+        assert!(invalid(|c| {
+            c.gyro.mount = [[1f32, 0f32, 0f32], [1f32, 0f32, 0f32], [0f32, 0f32, 1f32]]
+        }));
+    }
+
+    #[test]
+    fn reports_sections_that_need_a_restart() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.pointing.relative.dead_zone = 10f32;
+        new.gestures.enabled = true;
+        assert!(old.restart_needed(&new).is_empty());
+
+        new.dwell.enabled = true;
+        new.zoom.factor = 4;
+        assert_eq!(old.restart_needed(&new), vec!["dwell", "zoom"]);
+
+        // Every section must serialize to be compared.
+        let mut new = Config::default();
+        new.gyro.baud_rate = 9600;
+        new.presence.absence_timeout = 60f32;
+        new.tobii.wait_for_device = false;
+        new.gaze_scroll.enabled = true;
+        new.snapping.enabled = true;
+        new.screens.tracker = Some("DP-1".to_string());
+        new.screen_switch
+            .directions
+            .insert("HDMI-1".to_string(), [40f32, 0f32]);
+        assert_eq!(
+            old.restart_needed(&new),
+            vec![
+                "gyro",
+                "presence",
+                "tobii",
+                "gaze_scroll",
+                "snapping",
+                "screens",
+                "screen_switch"
+            ]
+        );
+    }
+}
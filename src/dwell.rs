@@ -0,0 +1,289 @@
+// Clicks when the cursor rests in one place.
+//
+// Once the cursor has stayed within `radius` of where it stopped for `time`,
+// the selected click type is performed there. The cursor then has to leave
+// the radius before the next dwell starts, and no dwell starts within
+// `cooldown` of a click, so that resting on a target does not click
+// repeatedly.
+//
+// Click types other than left click apply to the next dwell only. A drag
+// presses the button on the first dwell and releases it on the second.
+
+use cgmath::prelude::MetricSpace;
+use cgmath::{vec2, Vector2};
+
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::output::{Button, Output};
+use crate::overlay::Overlay;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClickType {
+    Left,
+    Right,
+    Double,
+    Drag,
+}
+
+pub struct Dwell {
+    config: config::Dwell,
+    overlay: Option<Overlay>,
+    // Where and when the cursor stopped, unless it has not moved since the
+    // last click.
+    origin: Option<(Vector2<f32>, Instant)>,
+    clicked_at: Vector2<f32>,
+    last_click: Option<Instant>,
+    click: ClickType,
+    dragging: bool,
+}
+
+impl Dwell {
+    pub fn new(config: config::Dwell) -> Dwell {
+        let overlay = if config.enabled && (config.indicator || config.palette) {
+            match Overlay::new(config.palette) {
+                Ok(overlay) => Some(overlay),
+                Err(e) => {
+                    println!("Could not create dwell overlay: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Dwell {
+            config,
+            overlay,
+            origin: None,
+            clicked_at: vec2(0f32, 0f32),
+            last_click: None,
+            click: ClickType::Left,
+            dragging: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Must be called regularly with the position [px] of the cursor, also
+    // while it does not move.
+    pub fn update(&mut self, now: Instant, position: Vector2<f32>, output: &mut dyn Output) {
+        if !self.config.enabled {
+            return;
+        }
+        if let Some(overlay) = self.overlay.as_mut() {
+            overlay.poll();
+        }
+
+        let (x, y) = (position.x as i32, position.y as i32);
+        let moved = match self.origin {
+            Some((origin, _)) => origin.distance(position) > self.config.radius,
+            None => self
+                .last_click
+                .map_or(true, |_| self.clicked_at.distance(position) > self.config.radius),
+        };
+        if moved {
+            self.origin = Some((position, now));
+        }
+
+        let started = match self.origin {
+            Some((_, started)) => started,
+            None => return self.hide(),
+        };
+        let cooldown = Duration::from_secs_f32(self.config.cooldown);
+        let start = match self.last_click {
+            Some(last) if last + cooldown > started => last + cooldown,
+            _ => started,
+        };
+        if now < start {
+            return self.hide();
+        }
+
+        let time = Duration::from_secs_f32(self.config.time);
+        let progress = now.duration_since(start).as_secs_f32() / time.as_secs_f32();
+        if progress < 1f32 {
+            if self.config.indicator {
+                if let Some(overlay) = self.overlay.as_mut() {
+                    overlay.show(x, y, progress);
+                }
+            }
+            return;
+        }
+
+        self.hide();
+        self.origin = None;
+        self.clicked_at = position;
+        self.last_click = Some(now);
+
+        if let Some(click) = self.overlay.as_ref().and_then(|o| o.palette_hit(x, y)) {
+            self.select(click);
+            return;
+        }
+        self.perform(output);
+    }
+
+    // Stops dwelling, e.g. because the user left.
+    pub fn cancel(&mut self) {
+        self.origin = None;
+        self.hide();
+    }
+
+    fn hide(&mut self) {
+        if let Some(overlay) = self.overlay.as_mut() {
+            overlay.hide();
+        }
+    }
+
+    fn select(&mut self, click: ClickType) {
+        println!("Dwell click type: {:?}", click);
+        self.click = click;
+        if let Some(overlay) = self.overlay.as_mut() {
+            overlay.select(click);
+        }
+    }
+
+    fn perform(&mut self, output: &mut dyn Output) {
+        match self.click {
+            ClickType::Left => output.click(Button::Left),
+            ClickType::Right => output.click(Button::Right),
+            ClickType::Double => {
+                output.click(Button::Left);
+                output.click(Button::Left);
+            }
+            ClickType::Drag => {
+                if !self.dragging {
+                    self.dragging = true;
+                    output.press(Button::Left);
+                    return;
+                }
+                self.dragging = false;
+                output.release(Button::Left);
+            }
+        }
+        if self.click != ClickType::Left {
+            self.select(ClickType::Left);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records presses and releases.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<(bool, Button)>,
+    }
+
+    impl Recorder {
+        fn clicks(&self) -> usize {
+            self.events.iter().filter(|(pressed, _)| !pressed).count()
+        }
+    }
+
+    impl Output for Recorder {
+        fn move_to(&mut self, _: i32, _: i32) {}
+
+        fn move_relative(&mut self, _: f32, _: f32) {}
+
+        fn location(&mut self) -> Option<(i32, i32)> {
+            None
+        }
+
+        fn press(&mut self, button: Button) {
+            self.events.push((true, button));
+        }
+
+        fn release(&mut self, button: Button) {
+            self.events.push((false, button));
+        }
+
+        fn scroll(&mut self, _: i32, _: i32) {}
+    }
+
+    // Dwells of 0.8 s within 20 px, with a cooldown of 1 s and no overlay.
+    fn dwell() -> Dwell {
+        Dwell::new(config::Dwell {
+            enabled: true,
+            indicator: false,
+            palette: false,
+            ..config::Dwell::default()
+        })
+    }
+
+    // Feeds `positions` at 10 Hz from `start` and returns the time after the
+    // last one.
+    fn feed(
+        dwell: &mut Dwell,
+        output: &mut Recorder,
+        start: Instant,
+        positions: &[(f32, f32)],
+    ) -> Instant {
+        let mut now = start;
+        for &(x, y) in positions {
+            dwell.update(now, vec2(x, y), output);
+            now += Duration::from_millis(100);
+        }
+        now
+    }
+
+    #[test]
+    fn clicks_after_resting_within_radius() {
+        let (mut dwell, mut output) = (dwell(), Recorder::default());
+        // Drifting by less than the radius.
+        let positions: Vec<_> = (0..8).map(|i| (100f32 + i as f32, 100f32)).collect();
+        let now = feed(&mut dwell, &mut output, Instant::now(), &positions);
+        assert_eq!(output.clicks(), 0);
+
+        feed(&mut dwell, &mut output, now, &[(108f32, 100f32)]);
+        assert_eq!(output.events, vec![(true, Button::Left), (false, Button::Left)]);
+    }
+
+    #[test]
+    fn moving_restarts_dwell() {
+        let (mut dwell, mut output) = (dwell(), Recorder::default());
+        let mut positions = vec![(100f32, 100f32); 6];
+        positions.extend(vec![(150f32, 100f32); 6]);
+        let now = feed(&mut dwell, &mut output, Instant::now(), &positions);
+        assert_eq!(output.clicks(), 0);
+
+        feed(&mut dwell, &mut output, now, &[(150f32, 100f32); 3]);
+        assert_eq!(output.clicks(), 1);
+    }
+
+    #[test]
+    fn resting_after_click_does_not_click_again() {
+        let (mut dwell, mut output) = (dwell(), Recorder::default());
+        feed(&mut dwell, &mut output, Instant::now(), &[(100f32, 100f32); 50]);
+        assert_eq!(output.clicks(), 1);
+    }
+
+    #[test]
+    fn no_dwell_during_cooldown() {
+        let (mut dwell, mut output) = (dwell(), Recorder::default());
+        let start = Instant::now();
+        let now = feed(&mut dwell, &mut output, start, &[(100f32, 100f32); 9]);
+        assert_eq!(output.clicks(), 1);
+
+        // The cooldown of 1 s after the click at 0.8 s delays the next dwell
+        // to 2.6 s, however early the cursor moved on.
+        let now = feed(&mut dwell, &mut output, now, &[(200f32, 100f32); 16]);
+        assert_eq!(output.clicks(), 1);
+        feed(&mut dwell, &mut output, now, &[(200f32, 100f32); 2]);
+        assert_eq!(output.clicks(), 2);
+    }
+
+    #[test]
+    fn cancel_restarts_dwell() {
+        let (mut dwell, mut output) = (dwell(), Recorder::default());
+        let now = feed(&mut dwell, &mut output, Instant::now(), &[(100f32, 100f32); 6]);
+        dwell.cancel();
+        let now = feed(&mut dwell, &mut output, now, &[(100f32, 100f32); 6]);
+        assert_eq!(output.clicks(), 0);
+        feed(&mut dwell, &mut output, now, &[(100f32, 100f32); 3]);
+        assert_eq!(output.clicks(), 1);
+    }
+}
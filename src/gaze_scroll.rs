@@ -0,0 +1,405 @@
+// Scrolls the window the user looks at when they look near one of its
+// edges, e.g. to read on at the bottom of a page.
+//
+// Each edge of the window has a zone of `zone` times its height (or width).
+// Looking into a zone for `delay` starts scrolling towards that edge, faster
+// the deeper the gaze is in the zone, up to `speed` steps per second.
+// Scrolling stops once the gaze has left the zone by more than `hysteresis`
+// times the zone, so that gaze jitter at its border does not start and stop
+// scrolling all the time.
+//
+// The window is the topmost one under the gaze, as reported by the X
+// server. If there is none, or it cannot be determined, the whole screen is
+// used. Looking it up takes a round trip per window, so it is done on a
+// thread of its own, and scrolling waits for the result when the gaze moves
+// to another window.
+
+use cgmath::Vector2;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{ConnectionExt as _, MapState, Window};
+use x11rb::rust_connection::RustConnection;
+
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::output::Subpixel;
+
+// How long the window under the gaze is remembered.
+const WINDOW_CACHE: Duration = Duration::from_millis(500);
+
+// Longest time [s] between two gaze samples that is scrolled over.
+const MAX_DT: f32 = 0.1f32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Area {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+impl Area {
+    fn contains(&self, p: Vector2<f32>) -> bool {
+        p.x >= self.x && p.x < self.x + self.width && p.y >= self.y && p.y < self.y + self.height
+    }
+
+    // How deep [fraction of the zone] `p` is in the zone of `edge`. Negative
+    // outside of the zone.
+    fn depth(&self, p: Vector2<f32>, edge: Edge, zone: f32) -> f32 {
+        let (distance, size) = match edge {
+            Edge::Top => (p.y - self.y, self.height),
+            Edge::Bottom => (self.y + self.height - p.y, self.height),
+            Edge::Left => (p.x - self.x, self.width),
+            Edge::Right => (self.x + self.width - p.x, self.width),
+        };
+        1f32 - distance / (size * zone)
+    }
+}
+
+struct Windows {
+    conn: RustConnection,
+    root: Window,
+}
+
+impl Windows {
+    fn new() -> Result<Windows, Box<dyn Error>> {
+        let (conn, screen) = RustConnection::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+        Ok(Windows { conn, root })
+    }
+
+    // Topmost visible top level window that contains `p`.
+    fn at(&self, p: Vector2<f32>) -> Result<Option<Area>, Box<dyn Error>> {
+        let tree = self.conn.query_tree(self.root)?.reply()?;
+        // Children are in stacking order, bottom first.
+        for &window in tree.children.iter().rev() {
+            let attributes = self.conn.get_window_attributes(window)?.reply()?;
+            // Override redirect windows are menus, tooltips and our own
+            // overlays.
+            if attributes.map_state != MapState::VIEWABLE || attributes.override_redirect {
+                continue;
+            }
+            let geometry = self.conn.get_geometry(window)?.reply()?;
+            let area = Area {
+                x: geometry.x as f32,
+                y: geometry.y as f32,
+                width: geometry.width as f32,
+                height: geometry.height as f32,
+            };
+            if area.contains(p) {
+                return Ok(Some(area));
+            }
+        }
+        Ok(None)
+    }
+
+    // Looks up the window at each requested point until the requests are
+    // closed, falling back to the screen sent along.
+    fn serve(
+        self,
+        requests: Receiver<(Vector2<f32>, Area)>,
+        window: Arc<Mutex<Option<(Area, Instant)>>>,
+    ) {
+        for (gaze, screen) in requests {
+            let area = match self.at(gaze) {
+                Ok(area) => area.unwrap_or(screen),
+                Err(e) => {
+                    println!("Could not look up window: {}", e);
+                    screen
+                }
+            };
+            *window.lock().unwrap() = Some((area, Instant::now()));
+        }
+    }
+}
+
+pub struct GazeScroll {
+    config: config::GazeScroll,
+    active: bool,
+    screen: Area,
+    // Requests to look up the window at a point, if windows can be looked
+    // up at all, and the last window looked up and when.
+    lookups: Option<SyncSender<(Vector2<f32>, Area)>>,
+    window: Arc<Mutex<Option<(Area, Instant)>>>,
+    // Edge being scrolled towards, and when the gaze entered its zone.
+    edge: Option<(Edge, Instant)>,
+    last: Option<Instant>,
+    steps: Subpixel,
+}
+
+impl GazeScroll {
+    // Takes the position and size [px] of the screen.
+    pub fn new(
+        config: config::GazeScroll,
+        origin: Vector2<f32>,
+        size: Vector2<f32>,
+    ) -> GazeScroll {
+        let screen = Area {
+            x: origin.x,
+            y: origin.y,
+            width: size.x,
+            height: size.y,
+        };
+        let window = Arc::new(Mutex::new(None));
+        let lookups = match Windows::new() {
+            Ok(windows) => {
+                // At most one lookup is queued, further requests are dropped
+                // while it is pending.
+                let (lookups, requests) = mpsc::sync_channel(1);
+                let window = window.clone();
+                thread::spawn(move || windows.serve(requests, window));
+                Some(lookups)
+            }
+            Err(e) => {
+                println!("Could not look up windows, scrolling at screen edges: {}", e);
+                None
+            }
+        };
+        GazeScroll::with_lookups(config, screen, lookups, window)
+    }
+
+    fn with_lookups(
+        config: config::GazeScroll,
+        screen: Area,
+        lookups: Option<SyncSender<(Vector2<f32>, Area)>>,
+        window: Arc<Mutex<Option<(Area, Instant)>>>,
+    ) -> GazeScroll {
+        GazeScroll {
+            active: config.enabled,
+            config,
+            screen,
+            lookups,
+            window,
+            edge: None,
+            last: None,
+            steps: Subpixel::new(),
+        }
+    }
+
+    // Takes the new position and size [px] of the screen.
+    pub fn resize(&mut self, origin: Vector2<f32>, size: Vector2<f32>) {
+        self.screen = Area {
+            x: origin.x,
+            y: origin.y,
+            width: size.x,
+            height: size.y,
+        };
+        *self.window.lock().unwrap() = None;
+        self.edge = None;
+    }
+
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+        self.edge = None;
+        println!("Gaze scrolling: {}", self.active);
+    }
+
+    // Area to scroll at `gaze`, or None while it is being looked up.
+    fn area(&self, now: Instant, gaze: Vector2<f32>) -> Option<Area> {
+        let lookups = match self.lookups.as_ref() {
+            Some(lookups) => lookups,
+            None => return Some(self.screen),
+        };
+        let window = *self.window.lock().unwrap();
+        let area = window.map(|(area, _)| area).filter(|area| area.contains(gaze));
+        let fresh = matches!(window, Some((_, at)) if now.saturating_duration_since(at) < WINDOW_CACHE);
+        if area.is_none() || !fresh {
+            // Keep using a stale window meanwhile, it rarely moves.
+            let _ = lookups.try_send((gaze, self.screen));
+        }
+        area
+    }
+
+    // Takes the gaze point [px] at `now`, and returns by how many steps to
+    // scroll horizontally and vertically.
+    pub fn update(&mut self, now: Instant, gaze: Vector2<f32>) -> (i32, i32) {
+        let dt = self
+            .last
+            .replace(now)
+            .map_or(0f32, |last| now.saturating_duration_since(last).as_secs_f32())
+            .min(MAX_DT);
+        if !self.active {
+            return (0, 0);
+        }
+
+        let area = match self.area(now, gaze) {
+            Some(area) => area,
+            None => return (0, 0),
+        };
+        let zone = self.config.zone;
+        if !area.contains(gaze) {
+            self.edge = None;
+            return (0, 0);
+        }
+
+        // Keep scrolling towards the current edge until the gaze has left
+        // its zone far enough, otherwise look for a zone the gaze is in.
+        if let Some((edge, _)) = self.edge {
+            if area.depth(gaze, edge, zone) < -self.config.hysteresis {
+                self.edge = None;
+            }
+        }
+        if self.edge.is_none() {
+            let mut edges = vec![Edge::Top, Edge::Bottom];
+            if self.config.horizontal {
+                edges.extend_from_slice(&[Edge::Left, Edge::Right]);
+            }
+            self.edge = edges
+                .into_iter()
+                .find(|edge| area.depth(gaze, *edge, zone) > 0f32)
+                .map(|edge| (edge, now));
+        }
+
+        let (edge, since) = match self.edge {
+            Some(edge) => edge,
+            None => return (0, 0),
+        };
+        if now.saturating_duration_since(since).as_secs_f32() < self.config.delay {
+            return (0, 0);
+        }
+
+        let depth = area.depth(gaze, edge, zone).clamp(0f32, 1f32);
+        let steps = self.config.speed * depth * dt;
+        match edge {
+            Edge::Top => self.steps.split(0f32, -steps),
+            Edge::Bottom => self.steps.split(0f32, steps),
+            Edge::Left => self.steps.split(-steps, 0f32),
+            Edge::Right => self.steps.split(steps, 0f32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::vec2;
+
+    // 1000 × 1000 px screen with a 100 px zone at each edge.
+    fn scroller() -> GazeScroll {
+        let config = config::GazeScroll {
+            enabled: true,
+            zone: 0.1f32,
+            hysteresis: 0.2f32,
+            delay: 0.3f32,
+            speed: 10f32,
+            horizontal: true,
+        };
+        let screen = Area {
+            x: 0f32,
+            y: 0f32,
+            width: 1000f32,
+            height: 1000f32,
+        };
+        GazeScroll::with_lookups(config, screen, None, Arc::new(Mutex::new(None)))
+    }
+
+    // Looks at `gaze` for `duration` [s] at 100 Hz from `start`, and returns
+    // the steps scrolled and when it stopped looking.
+    fn look(
+        scroll: &mut GazeScroll,
+        start: Instant,
+        duration: f32,
+        gaze: Vector2<f32>,
+    ) -> ((i32, i32), Instant) {
+        let mut total = (0, 0);
+        let mut now = start;
+        for i in 0..(duration * 100f32).round() as u32 {
+            now = start + Duration::from_millis(10 * i as u64);
+            let (x, y) = scroll.update(now, gaze);
+            total = (total.0 + x, total.1 + y);
+        }
+        (total, now)
+    }
+
+    #[test]
+    fn no_scrolling_in_the_middle() {
+        let mut scroll = scroller();
+        let (steps, _) = look(&mut scroll, Instant::now(), 2f32, vec2(500f32, 500f32));
+        assert_eq!(steps, (0, 0));
+    }
+
+    #[test]
+    fn scrolls_after_the_delay() {
+        let mut scroll = scroller();
+        let start = Instant::now();
+        let (steps, end) = look(&mut scroll, start, 0.29f32, vec2(500f32, 0f32));
+        assert_eq!(steps, (0, 0));
+        // 1 s at full speed after the delay.
+        let (steps, _) = look(&mut scroll, end, 1f32, vec2(500f32, 0f32));
+        assert!((-11..=-9).contains(&steps.1), "{:?}", steps);
+        assert_eq!(steps.0, 0);
+    }
+
+    #[test]
+    fn speed_scales_with_depth() {
+        let mut scroll = scroller();
+        // Halfway into the bottom zone: half the speed.
+        let (steps, _) = look(&mut scroll, Instant::now(), 1.3f32, vec2(500f32, 950f32));
+        assert!((4..=6).contains(&steps.1), "{:?}", steps);
+
+        let mut scroll = scroller();
+        // All the way into the right zone: full speed.
+        let (steps, _) = look(&mut scroll, Instant::now(), 1.3f32, vec2(999f32, 500f32));
+        assert!((9..=11).contains(&steps.0), "{:?}", steps);
+    }
+
+    #[test]
+    fn hysteresis_keeps_the_edge() {
+        let mut scroll = scroller();
+        let (_, end) = look(&mut scroll, Instant::now(), 0.5f32, vec2(500f32, 0f32));
+        // Just out of the zone, within the hysteresis: nothing to scroll, but
+        // back in the zone scrolling goes on without another delay.
+        let (steps, end) = look(&mut scroll, end, 0.5f32, vec2(500f32, 110f32));
+        assert_eq!(steps, (0, 0));
+        let (steps, end) = look(&mut scroll, end, 0.2f32, vec2(500f32, 0f32));
+        assert!(steps.1 < 0, "{:?}", steps);
+
+        // Beyond the hysteresis the edge is left, and entering the zone again
+        // waits for the delay.
+        let (_, end) = look(&mut scroll, end, 0.5f32, vec2(500f32, 150f32));
+        let (steps, _) = look(&mut scroll, end, 0.2f32, vec2(500f32, 0f32));
+        assert_eq!(steps, (0, 0));
+    }
+
+    #[test]
+    fn scrolls_the_window_looked_up() {
+        let (lookups, requests) = mpsc::sync_channel(1);
+        let window = Arc::new(Mutex::new(None));
+        let mut scroll = scroller();
+        scroll.lookups = Some(lookups);
+        scroll.window = window.clone();
+        let start = Instant::now();
+
+        // Nothing is scrolled until the window is looked up.
+        let gaze = vec2(500f32, 210f32);
+        let (steps, end) = look(&mut scroll, start, 0.5f32, gaze);
+        assert_eq!(steps, (0, 0));
+        assert_eq!(requests.try_recv().ok().map(|(p, _)| p), Some(gaze));
+        assert!(requests.try_recv().is_err());
+
+        // The gaze is near the top of a window at (200, 200).
+        let area = Area {
+            x: 200f32,
+            y: 200f32,
+            width: 600f32,
+            height: 600f32,
+        };
+        *window.lock().unwrap() = Some((area, end));
+        let (steps, _) = look(&mut scroll, end, 1f32, gaze);
+        assert!(steps.1 < 0, "{:?}", steps);
+        // Meanwhile the window has gone stale and is looked up again.
+        assert_eq!(requests.try_recv().ok().map(|(p, _)| p), Some(gaze));
+    }
+}
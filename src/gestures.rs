@@ -0,0 +1,350 @@
+// Recognizes head gestures in the angular velocity of the head.
+//
+// Motion is split into swings: stretches during which the head turns around
+// one axis faster than `speed`. Swings that follow each other closely and
+// alternate in direction around the same axis form a gesture:
+//
+//  * `swings` or more around the pitch axis are a nod, around the yaw axis a
+//    shake.
+//  * Any swing around the roll axis is a tilt, in the direction of the first
+//    swing.
+//  * Fewer swings around the pitch or yaw axis are a flick if the first one
+//    was faster than `flick_speed` and shorter than `flick_time`. Everything
+//    else is regular pointing and ignored.
+//
+// After a gesture, motion is ignored for `cooldown`, so that the head coming
+// to rest does not start another one.
+
+use cgmath::Vector3;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Nod,
+    Shake,
+    TiltLeft,
+    TiltRight,
+    FlickLeft,
+    FlickRight,
+    FlickUp,
+    FlickDown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    None,
+    LeftClick,
+    RightClick,
+    // Turning the head scrolls instead of moving the cursor, until the
+    // gesture is repeated.
+    ScrollMode,
+    // Start or stop scrolling when looking near the edge of a window.
+    GazeScroll,
+    // Stop or resume moving the cursor.
+    Toggle,
+    // Make the current head pose the neutral pose of absolute pointing.
+    Recenter,
+    // Magnify the area around the gaze, see `zoom`.
+    Zoom,
+}
+
+// Axes of the head frame, see `config::Gyro::mount`.
+const YAW: usize = 0;
+const PITCH: usize = 1;
+const ROLL: usize = 2;
+
+struct Swing {
+    axis: usize,
+    positive: bool,
+    start: Instant,
+    end: Option<Instant>,
+    // Fastest angular velocity [°/s] during the swing.
+    peak: f32,
+}
+
+pub struct Recognizer {
+    config: config::Gestures,
+    swings: Vec<Swing>,
+    quiet_until: Option<Instant>,
+}
+
+impl Recognizer {
+    pub fn new(config: config::Gestures) -> Recognizer {
+        Recognizer {
+            config,
+            swings: vec![],
+            quiet_until: None,
+        }
+    }
+
+    // Takes the angular velocity [°/s] of the head around the yaw (turning
+    // right), pitch (looking down) and roll axes, and returns the gesture it
+    // completes, if any.
+    pub fn update(&mut self, now: Instant, velocity: Vector3<f32>) -> Option<Gesture> {
+        if !self.config.enabled {
+            return None;
+        }
+        if let Some(until) = self.quiet_until {
+            if now < until {
+                return None;
+            }
+            self.quiet_until = None;
+        }
+
+        let speed = self.config.speed;
+        let velocities = [velocity.x, velocity.y, velocity.z];
+
+        // Follow the current swing until it slows down or reverses.
+        if let Some(swing) = self.swings.last_mut().filter(|s| s.end.is_none()) {
+            let v = velocities[swing.axis];
+            if v.abs() < speed / 2f32 || (v > 0f32) != swing.positive {
+                swing.end = Some(now);
+            } else {
+                swing.peak = swing.peak.max(v.abs());
+            }
+        }
+
+        let gesture = self.finish(now);
+        if gesture.is_some() {
+            return gesture;
+        }
+
+        let open = self.swings.last().map_or(false, |s| s.end.is_none());
+        let axis = (0..3)
+            .max_by(|&a, &b| {
+                velocities[a]
+                    .abs()
+                    .partial_cmp(&velocities[b].abs())
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap();
+        let v = velocities[axis];
+        if open || v.abs() < speed {
+            return None;
+        }
+
+        if let Some(last) = self.swings.last() {
+            if last.axis != axis {
+                // Motion around another axis ends the gesture.
+                let gesture = self.classify();
+                self.swings.clear();
+                if gesture.is_some() {
+                    self.quiet_until = Some(now + self.cooldown());
+                    return gesture;
+                }
+            } else if last.positive == (v > 0f32) {
+                // Turning on in the same direction is not a new swing.
+                return None;
+            }
+        }
+        self.swings.push(Swing {
+            axis,
+            positive: v > 0f32,
+            start: now,
+            end: None,
+            peak: v.abs(),
+        });
+        None
+    }
+
+    // Whether swings were seen that may still turn out to be a gesture.
+    pub fn is_pending(&self) -> bool {
+        !self.swings.is_empty()
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_secs_f32(self.config.cooldown)
+    }
+
+    // Ends the gesture once enough swings were seen, no swing followed the
+    // last one within `gap`, or it has taken longer than `window`.
+    fn finish(&mut self, now: Instant) -> Option<Gesture> {
+        let first = self.swings.first()?;
+        let last = self.swings.last()?;
+        let complete = self.swings.len() >= self.config.swings && last.end.is_some();
+        let paused = last
+            .end
+            .map_or(false, |end| now.duration_since(end).as_secs_f32() > self.config.gap);
+        let expired = now.duration_since(first.start).as_secs_f32() > self.config.window;
+        if !complete && !paused && !expired {
+            return None;
+        }
+
+        let gesture = self.classify();
+        self.swings.clear();
+        if gesture.is_some() {
+            self.quiet_until = Some(now + self.cooldown());
+        }
+        gesture
+    }
+
+    fn classify(&self) -> Option<Gesture> {
+        let first = self.swings.first()?;
+        match first.axis {
+            ROLL if first.positive => return Some(Gesture::TiltRight),
+            ROLL => return Some(Gesture::TiltLeft),
+            _ => (),
+        }
+
+        if self.swings.len() >= self.config.swings {
+            return Some(if first.axis == PITCH {
+                Gesture::Nod
+            } else {
+                Gesture::Shake
+            });
+        }
+
+        let duration = first.end?.duration_since(first.start).as_secs_f32();
+        if first.peak < self.config.flick_speed || duration > self.config.flick_time {
+            return None;
+        }
+        Some(match (first.axis, first.positive) {
+            (YAW, true) => Gesture::FlickRight,
+            (YAW, false) => Gesture::FlickLeft,
+            (_, true) => Gesture::FlickDown,
+            (_, false) => Gesture::FlickUp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::vec3;
+    use std::f32::consts::PI;
+
+    // Samples arrive at 100 Hz.
+    const RATE: f32 = 100f32;
+
+    fn recognizer() -> Recognizer {
+        Recognizer::new(config::Gestures {
+            enabled: true,
+            ..config::Gestures::default()
+        })
+    }
+
+    // Feeds a trace of angular velocities [°/s] (yaw, pitch, roll), followed
+    // by a second of rest, and returns the gestures recognized.
+    fn recognize(trace: Vec<Vector3<f32>>) -> Vec<Gesture> {
+        let mut recognizer = recognizer();
+        let start = Instant::now();
+        let rest = vec![vec3(0f32, 0f32, 0f32); RATE as usize];
+        trace
+            .into_iter()
+            .chain(rest)
+            .enumerate()
+            .filter_map(|(i, velocity)| {
+                let now = start + Duration::from_secs_f32(i as f32 / RATE);
+                recognizer.update(now, velocity)
+            })
+            .collect()
+    }
+
+    // Oscillation around `axis` with `peak` [°/s] at `frequency` [Hz], for
+    // `cycles` full back and forth movements.
+    fn oscillation(axis: usize, peak: f32, frequency: f32, cycles: f32) -> Vec<Vector3<f32>> {
+        let samples = (cycles / frequency * RATE) as usize;
+        (0..samples)
+            .map(|i| {
+                let mut velocity = [0f32; 3];
+                velocity[axis] = peak * (2f32 * PI * frequency * i as f32 / RATE).sin();
+                vec3(velocity[0], velocity[1], velocity[2])
+            })
+            .collect()
+    }
+
+    // Constant angular velocity around `axis` for `duration` [s].
+    fn turn(axis: usize, velocity: f32, duration: f32) -> Vec<Vector3<f32>> {
+        let mut v = [0f32; 3];
+        v[axis] = velocity;
+        vec![vec3(v[0], v[1], v[2]); (duration * RATE) as usize]
+    }
+
+    #[test]
+    fn nod() {
+        assert_eq!(
+            recognize(oscillation(PITCH, 150f32, 2.5f32, 2f32)),
+            vec![Gesture::Nod]
+        );
+    }
+
+    #[test]
+    fn shake() {
+        assert_eq!(
+            recognize(oscillation(YAW, 150f32, 2.5f32, 2f32)),
+            vec![Gesture::Shake]
+        );
+    }
+
+    #[test]
+    fn tilt() {
+        assert_eq!(
+            recognize(turn(ROLL, 100f32, 0.2f32)),
+            vec![Gesture::TiltRight]
+        );
+        assert_eq!(
+            recognize(turn(ROLL, -100f32, 0.2f32)),
+            vec![Gesture::TiltLeft]
+        );
+    }
+
+    #[test]
+    fn flick() {
+        assert_eq!(
+            recognize(turn(YAW, 250f32, 0.1f32)),
+            vec![Gesture::FlickRight]
+        );
+        assert_eq!(
+            recognize(turn(PITCH, -250f32, 0.1f32)),
+            vec![Gesture::FlickUp]
+        );
+    }
+
+    #[test]
+    fn slow_turns_are_not_gestures() {
+        assert!(recognize(turn(YAW, 40f32, 1f32)).is_empty());
+        assert!(recognize(oscillation(PITCH, 40f32, 1f32, 3f32)).is_empty());
+    }
+
+    #[test]
+    fn fast_pointing_is_not_a_gesture() {
+        // A single swing that is too slow and long for a flick.
+        assert!(recognize(turn(YAW, 120f32, 0.4f32)).is_empty());
+        // Two swings are not enough for a shake.
+        assert!(recognize(oscillation(YAW, 150f32, 2.5f32, 1f32)).is_empty());
+    }
+
+    #[test]
+    fn noise_is_not_a_gesture() {
+        // Deterministic noise on all axes, below the swing speed.
+        let mut state = 12345u32;
+        let mut noise = || {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            ((state >> 16) as f32 / 32768f32 - 1f32) * 50f32
+        };
+        let trace = (0..500).map(|_| vec3(noise(), noise(), noise())).collect();
+        assert!(recognize(trace).is_empty());
+    }
+
+    #[test]
+    fn pending_while_swinging() {
+        let mut recognizer = recognizer();
+        let start = Instant::now();
+        assert!(recognizer.update(start, vec3(0f32, 100f32, 0f32)).is_none());
+        assert!(recognizer.is_pending());
+        // The swing ends, but may still be followed by another one.
+        let end = start + Duration::from_millis(100);
+        recognizer.update(end, vec3(0f32, 0f32, 0f32));
+        assert!(recognizer.is_pending());
+        let later = start + Duration::from_secs(1);
+        recognizer.update(later, vec3(0f32, 0f32, 0f32));
+        assert!(!recognizer.is_pending());
+    }
+}
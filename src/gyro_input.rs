@@ -1,23 +1,17 @@
-use std::ffi::{CStr, CString};
-use std::mem;
-use std::os::raw;
-use std::ptr;
-use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::mpsc::{Receiver, TryRecvError};
 
-use crate::inputs::{Input, InputAction};
+use crate::ahrs::{Ahrs, Madgwick, Mahony};
+use crate::bus;
+use crate::clock::Clock;
+use crate::config;
+use crate::inputs::{Event, Input, InputAction};
 
-use std::{env, io, str};
-use tokio_io::codec::{Decoder, Encoder, Framed};
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
 
-use bytes::{ByteOrder, BytesMut, LittleEndian};
-use std::process::Command;
-
-use crate::tokio::prelude::{Future, Stream};
-
-#[cfg(unix)]
-const DEFAULT_TTY: &str = "/dev/ttyUSB0";
-#[cfg(windows)]
-const DEFAULT_TTY: &str = "COM1";
+use cgmath::prelude::Matrix;
+use cgmath::{vec3, Matrix3, Quaternion, Vector3};
+use serialport::SerialPort;
 
 // TODO:
 //  - Set return content (7.2.8) to only contain the packages we are interested in.
@@ -34,150 +28,377 @@ const DEFAULT_TTY: &str = "COM1";
 //   7.1.2   0x51   Acceleration      [m / s²] * 3
 //   7.1.3   0x52   Angular Velocity  [° / s ] * 3
 //   7.1.4   0x53   Angle             [°     ] * 3
-//   7.1.5   0x54   Magnetic          [ TODO ] * 3
+//   7.1.5   0x54   Magnetic          [ raw  ] * 3
 //   7.1.10  0x59   Quaternion        [ TODO ] * ?
 //   ...
 //
 // Other discriminators which I am not interested in:
 //   0x55, 0x56, 0x57, 0x58, 0x5A
 
-enum MeasurementFrameType {
-    Acceleration,    // [m / s²]
-    Angle,           // [°]
-    Magnetic,        // [?] TODO
-    AngularVelocity, // [° / s]
+const FRAME_START: u8 = 0x55;
+const FRAME_LENGTH: usize = 11;
+
+// How long a read may block, which bounds how long it takes to react to
+// actions.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Time frames only carry the time of day.
+const DAY_US: i64 = 24 * 60 * 60 * 1000 * 1000;
+
+// Rotation from the frame of the IMU to the frame of the head, see
+// `config::Gyro::mount`.
+pub fn mount(config: &config::Gyro) -> Matrix3<f32> {
+    let rows = config.mount;
+    Matrix3::from_cols(rows[0].into(), rows[1].into(), rows[2].into()).transpose()
 }
 
-const FRAME_START: u8 = 0x55;
+// Sensor fusion filter, see `config::Gyro::filter`.
+pub fn filter(config: &config::Gyro) -> Box<dyn Ahrs + Send> {
+    match config.filter {
+        config::Filter::Madgwick => Box::new(Madgwick::new(config.beta)),
+        config::Filter::Mahony => Box::new(Mahony::new(config.kp, config.ki)),
+    }
+}
+
+// Splits the byte stream of the IMU into frames and decodes them.
+struct Decoder {
+    output: bus::Sender,
+    // Bytes that do not form a complete frame yet.
+    buffer: Vec<u8>,
+    mount: Matrix3<f32>,
 
-struct LineCodec {
-    output: SyncSender<Input>,
+    // Time [µs] of the most recent time frame, counted from midnight of the
+    // first one, and its mapping to the host clock. Without time frames,
+    // inputs are timestamped when they are decoded.
+    clock: Clock,
+    device_time: Option<i64>,
+    days: i64,
+
+    // Sensor fusion state. Acceleration and magnetic field arrive in their
+    // own frames, so we remember the latest of each and run the filter
+    // whenever a new angular velocity frame arrives.
+    fusion: Box<dyn Ahrs + Send>,
+    acceleration: Option<Vector3<f32>>,
+    magnetic: Option<Vector3<f32>>,
+    last_angular_velocity: Option<Instant>,
 }
 
-impl Decoder for LineCodec {
-    type Item = String;
-    type Error = io::Error;
+impl Decoder {
+    fn new(output: bus::Sender, mount: Matrix3<f32>, fusion: Box<dyn Ahrs + Send>) -> Decoder {
+        Decoder {
+            output,
+            buffer: Vec::with_capacity(2 * FRAME_LENGTH),
+            mount,
+            clock: Clock::new(),
+            device_time: None,
+            days: 0,
+            fusion,
+            acceleration: None,
+            magnetic: None,
+            last_angular_velocity: None,
+        }
+    }
+
+    fn time(&self) -> Instant {
+        self.device_time
+            .and_then(|t| self.clock.to_host(t))
+            .unwrap_or_else(Instant::now)
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() == 0 {
-            return Ok(None);
+    fn send(&self, input: Input) {
+        let _ = self.output.send(Event::new(self.time(), input));
+    }
+
+    fn fuse(&mut self, angular_velocity: Vector3<f32>) {
+        let tick = self.time();
+        let last = self.last_angular_velocity.replace(tick);
+
+        let (acceleration, last) = match (self.acceleration, last) {
+            (Some(acceleration), Some(last)) => (acceleration, last),
+            _ => return,
+        };
+
+        let dur = tick.saturating_duration_since(last);
+        let dt = dur.as_secs() as f32 + dur.subsec_nanos() as f32 * 1.0e-9;
+        // Several frames may share the same time frame.
+        if dt == 0f32 {
+            return;
         }
 
-        match src.iter().position(|b| *b == FRAME_START) {
-            None => Ok(None),
-            Some(offset) => {
-                let mut frame = src.split_to(offset);
-                src.split_to(1); // Consume FRAME_START too.
+        let gyro = vec3(
+            angular_velocity.x.to_radians(),
+            angular_velocity.y.to_radians(),
+            angular_velocity.z.to_radians(),
+        );
+        self.fusion.update(gyro, acceleration, self.magnetic, dt);
 
-                if frame.len() != 10 {
-                    // TODO: Maybe warn that we got a frame that looks weird?
-                    if frame.len() != 0 {
-                        println!("ignoring frame of length {}", frame.len());
-                    }
-                    return Ok(None);
+        // The filter estimates the orientation of the IMU, rotate it so that
+        // it describes the orientation of the head instead.
+        let q = self.fusion.orientation() * Quaternion::from(self.mount).conjugate();
+        self.send(Input::HeadOrientation {
+            w: q.s,
+            x: q.v.x,
+            y: q.v.y,
+            z: q.v.z,
+        });
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut consumed = 0;
+        loop {
+            let begin = match self.buffer[consumed..].iter().position(|b| *b == FRAME_START) {
+                Some(offset) => consumed + offset,
+                None => {
+                    consumed = self.buffer.len();
+                    break;
                 }
+            };
+            if self.buffer.len() - begin < FRAME_LENGTH {
+                consumed = begin;
+                break;
+            }
 
-                // TODO: Check against checksum.
-                frame.split_off(9);
-
-                let discriminator = frame.split_to(1)[0];
-
-                match discriminator {
-                    0x51 => {
-                        // Acceleration
-                        // Python script also multiplies by 16.
-                        let x = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 16f32;
-                        frame.advance(2);
-                        let y = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 16f32;
-                        frame.advance(2);
-                        let z = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 16f32;
-                        frame.advance(2);
-                        //println!("acc {} {} {}", x, y, z);
-                        //self.output.send(Input::Gyro{x: z - 0.315f32, y: y});
-                        Ok(Some("Attempted decode".to_string()))
-                    }
-                    0x52 => {
-                        // Angular Velocity
-                        // Rotation of head left/right
-                        let x = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 2000f32;
-                        frame.advance(2);
-                        let y = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 2000f32;
-                        frame.advance(2);
-                        // Rotation of head up/down
-                        let z = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 2000f32;
-                        frame.advance(2);
-                        let temp = (LittleEndian::read_i16(&mut frame) as f32) / 100f32;
-                        //println!("anv {0:6.3} {1:6.3} {2:6.3}", x, y, z);
-                        self.output.send(Input::Gyro { x, y: z });
-                        Ok(Some("Attempted decode".to_string()))
-                    }
-                    0x53 => {
-                        // Angle
-                        // Python Script also multiplies by 170
-                        // x (east)
-                        let roll = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 180f32;
-                        frame.advance(2);
-                        // y (north)
-                        let pitch = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 180f32;
-                        frame.advance(2);
-                        // z (toward sky)
-                        let yaw = (LittleEndian::read_i16(&mut frame) as f32) / 32768f32 * 180f32;
-                        frame.advance(2);
-                        let temp = (LittleEndian::read_i16(&mut frame) as f32) / 100f32;
-                        // println!("yaw {0:6.3}    pitch {1:6.3}     ignore {2:6.3}", yaw, roll, pitch);
-                        self.output.send(Input::HeadAngle {
-                            roll,
-                            pitch,
-                            yaw,
-                        });
-                        //move_cursor_relative((z * -1.5f32) as i32, x as i32);
-                        Ok(Some("Attempted decode".to_string()))
-                    }
-                    _ => {
-                        // TODO: Maybe warn that we are dropping a frame?
-                        //println!("ignoring frame with discriminator {:x}", discriminator);
-                        Ok(None)
+            let mut frame = [0u8; FRAME_LENGTH];
+            frame.copy_from_slice(&self.buffer[begin..begin + FRAME_LENGTH]);
+            let checksum = frame[..FRAME_LENGTH - 1]
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if checksum != frame[FRAME_LENGTH - 1] {
+                // FRAME_START also occurs within frames, so this may not have
+                // been the start of a frame. Look for the next one.
+                consumed = begin + 1;
+                continue;
+            }
+
+            self.decode(frame[1], &frame[2..FRAME_LENGTH - 1]);
+            consumed = begin + FRAME_LENGTH;
+        }
+        self.buffer.drain(..consumed);
+    }
+
+    fn decode(&mut self, discriminator: u8, data: &[u8]) {
+        // Measurements are three (sometimes four) little endian i16.
+        let value = |i: usize| i16::from_le_bytes([data[2 * i], data[2 * i + 1]]) as f32;
+
+        match discriminator {
+            0x50 => {
+                // Time: year, month, day, hour, minute, second, millisecond
+                let (hour, minute, second) = (data[3] as i64, data[4] as i64, data[5] as i64);
+                let millis = u16::from_le_bytes([data[6], data[7]]) as i64;
+                let mut time = (((hour * 60 + minute) * 60 + second) * 1000 + millis) * 1000;
+                if let Some(last) = self.device_time {
+                    if time + self.days * DAY_US < last - DAY_US / 2 {
+                        self.days += 1;
                     }
                 }
+                time += self.days * DAY_US;
+                self.device_time = Some(time);
+                self.clock.observe(time, Instant::now());
+            }
+            0x51 => {
+                // Acceleration
+                // Python script also multiplies by 16.
+                let x = value(0) / 32768f32 * 16f32;
+                let y = value(1) / 32768f32 * 16f32;
+                let z = value(2) / 32768f32 * 16f32;
+                //println!("acc {} {} {}", x, y, z);
+                self.acceleration = Some(vec3(x, y, z));
+            }
+            0x52 => {
+                // Angular Velocity
+                let x = value(0) / 32768f32 * 2000f32;
+                let y = value(1) / 32768f32 * 2000f32;
+                let z = value(2) / 32768f32 * 2000f32;
+                //println!("anv {0:6.3} {1:6.3} {2:6.3}", x, y, z);
+                let head = self.mount * vec3(x, y, z);
+                self.send(Input::Gyro {
+                    x: head.x,
+                    y: head.y,
+                    z: head.z,
+                });
+                self.fuse(vec3(x, y, z));
+            }
+            0x53 => {
+                // Angle
+                // Python Script also multiplies by 170
+                // x (east)
+                let roll = value(0) / 32768f32 * 180f32;
+                // y (north)
+                let pitch = value(1) / 32768f32 * 180f32;
+                // z (toward sky)
+                let yaw = value(2) / 32768f32 * 180f32;
+                self.send(Input::HeadAngle { roll, pitch, yaw });
+            }
+            0x54 => {
+                // Magnetic
+                // Only the direction of the field is used for fusion, so
+                // we do not bother converting to a physical unit.
+                let x = value(0);
+                let y = value(1);
+                let z = value(2);
+                //println!("mag {0:6.3} {1:6.3} {2:6.3}", x, y, z);
+                self.magnetic = Some(vec3(x, y, z));
+            }
+            _ => {
+                //println!("ignoring frame with discriminator {:x}", discriminator);
             }
         }
     }
 }
 
-impl Encoder for LineCodec {
-    type Item = String;
-    type Error = io::Error;
+#[cfg(unix)]
+fn open(config: &config::Gyro) -> serialport::Result<Box<dyn SerialPort>> {
+    let mut port = serialport::new(config.tty.as_str(), config.baud_rate)
+        .timeout(READ_TIMEOUT)
+        .open_native()?;
+    // Do not lock out other programs, e.g. for configuring the IMU.
+    port.set_exclusive(false)?;
+    Ok(Box::new(port))
+}
 
-    fn encode(&mut self, _item: Self::Item, _dst: &mut BytesMut) -> Result<(), Self::Error> {
-        Ok(())
-    }
+#[cfg(not(unix))]
+fn open(config: &config::Gyro) -> serialport::Result<Box<dyn SerialPort>> {
+    serialport::new(config.tty.as_str(), config.baud_rate)
+        .timeout(READ_TIMEOUT)
+        .open()
 }
 
-pub fn listen(output: SyncSender<Input>, inbox: Receiver<InputAction>) {
-    let tty_path: &str = DEFAULT_TTY.into();
+// Reads frames from `port` until told to shut down or the port fails. Reads
+// must time out regularly for actions to be handled. Any reader will do, so
+// this also runs against a pseudo terminal.
+fn read<R: Read>(
+    mut port: R,
+    mut decoder: Decoder,
+    inbox: &Receiver<InputAction>,
+) -> io::Result<()> {
+    let mut buffer = [0u8; 256];
+    let mut paused = false;
+    loop {
+        match inbox.try_recv() {
+            Ok(InputAction::Shutdown) | Err(TryRecvError::Disconnected) => return Ok(()),
+            Ok(InputAction::Pause) => paused = true,
+            Ok(InputAction::Resume) => {
+                paused = false;
+                // Do not integrate over the pause.
+                decoder.last_angular_velocity = None;
+            }
+            Err(TryRecvError::Empty) => (),
+        }
+
+        match port.read(&mut buffer) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            // Keep reading while paused, so that stale frames do not pile up.
+            Ok(_) if paused => (),
+            Ok(n) => decoder.feed(&buffer[..n]),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-    let settings = tokio_serial::SerialPortSettings {
-        baud_rate: 921600, // default is 9200
-        data_bits: tokio_serial::DataBits::Eight,
-        flow_control: tokio_serial::FlowControl::None,
-        parity: tokio_serial::Parity::None,
-        stop_bits: tokio_serial::StopBits::One,
-        timeout: std::time::Duration::from_millis(1000),
+pub fn listen(output: bus::Sender, inbox: Receiver<InputAction>, config: config::Gyro) {
+    let port = match open(&config) {
+        Ok(port) => port,
+        Err(e) => {
+            println!("Could not open {}: {}", config.tty, e);
+            return;
+        }
     };
-    let mut port = tokio_serial::Serial::from_path(tty_path, &settings).unwrap();
 
-    #[cfg(unix)]
-    port.set_exclusive(false)
-        .expect("Unable to set serial port exlusive");
+    let decoder = Decoder::new(output, mount(&config), filter(&config));
+    if let Err(e) = read(port, decoder, &inbox) {
+        println!("Could not read from {}: {}", config.tty, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::SquareMatrix;
+    use serialport::TTYPort;
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // A frame of three measurements with a valid checksum.
+    fn frame(discriminator: u8, values: [i16; 3]) -> Vec<u8> {
+        let mut frame = vec![FRAME_START, discriminator];
+        for value in values.iter() {
+            frame.extend_from_slice(&value.to_le_bytes());
+        }
+        frame.extend_from_slice(&[0, 0]);
+        let checksum = frame.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        frame.push(checksum);
+        frame
+    }
+
+    // A time frame at the given time of day.
+    fn time_frame(hour: u8, minute: u8, second: u8, millis: u16) -> Vec<u8> {
+        let mut frame = vec![FRAME_START, 0x50, 26, 10, 18, hour, minute, second];
+        frame.extend_from_slice(&millis.to_le_bytes());
+        let checksum = frame.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        frame.push(checksum);
+        frame
+    }
 
-    let (_, reader) = LineCodec{output}.framed(port).split();
+    #[test]
+    fn reads_frames_from_pty() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        let rx = bus::new();
+        let decoder = Decoder::new(
+            rx.sender("gyro"),
+            Matrix3::identity(),
+            filter(&config::Gyro::default()),
+        );
+        let (actions, inbox) = mpsc::channel();
+        let reader = thread::spawn(move || read(slave, decoder, &inbox));
 
-    let printer = reader
-        .for_each(|s| {
-            println!("{:?}", s);
-            Ok(())
-        })
-        .map_err(|e| eprintln!("{}", e));
+        let mut corrupt = frame(0x52, [1000, 0, 0]);
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        master.write_all(&corrupt).unwrap();
+        master.write_all(&frame(0x52, [16384, -16384, 0])).unwrap();
+        master.write_all(&frame(0x53, [0, 0, 8192])).unwrap();
 
-    tokio::run(printer);
+        // The corrupt frame is skipped, the others arrive in order.
+        match rx.recv_timeout(Duration::from_secs(2)).unwrap().input {
+            Input::Gyro { x, y, z } => assert_eq!((x, y, z), (1000f32, -1000f32, 0f32)),
+            _ => panic!("expected angular velocity"),
+        }
+        match rx.recv_timeout(Duration::from_secs(2)).unwrap().input {
+            Input::HeadAngle { roll, pitch, yaw } => {
+                assert_eq!((roll, pitch, yaw), (0f32, 0f32, 45f32))
+            }
+            _ => panic!("expected angle"),
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+        actions.send(InputAction::Shutdown).unwrap();
+        reader.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn time_rolls_over_at_midnight() {
+        let rx = bus::new();
+        let mut decoder = Decoder::new(
+            rx.sender("gyro"),
+            Matrix3::identity(),
+            filter(&config::Gyro::default()),
+        );
+        let before = ((23 * 60 + 59) * 60 + 59) * 1_000_000 + 900_000;
+
+        decoder.feed(&time_frame(23, 59, 59, 900));
+        assert_eq!(decoder.device_time, Some(before));
+        decoder.feed(&time_frame(0, 0, 0, 100));
+        assert_eq!(decoder.device_time, Some(before + 200_000));
+        assert_eq!(decoder.days, 1);
+        // Going back a little is not another day.
+        decoder.feed(&time_frame(0, 0, 0, 50));
+        assert_eq!(decoder.device_time, Some(before + 150_000));
+        assert_eq!(decoder.days, 1);
+    }
 }
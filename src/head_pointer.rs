@@ -0,0 +1,183 @@
+// Absolute pointing: head yaw and pitch relative to a neutral pose are
+// mapped onto the screen, so that looking straight ahead puts the cursor in
+// the center and turning the head by a configured angle puts it on an edge.
+
+use cgmath::prelude::Rotation;
+use cgmath::{vec2, Quaternion, Vector2, Vector3};
+
+use crate::config::AbsolutePointing;
+
+// Direction the head faces, in the frame of the head (see
+// `config::Gyro::mount`).
+const FORWARD: Vector3<f32> = Vector3 {
+    x: 0f32,
+    y: 0f32,
+    z: 1f32,
+};
+
+// Yaw and pitch [°] of a fused orientation. The filter's earth frame has z
+// pointing up, so yaw is the heading of the forward direction in the
+// horizontal plane (negated, so that turning right is positive) and pitch is
+// its elevation.
+pub fn yaw_pitch(q: Quaternion<f32>) -> (f32, f32) {
+    let forward = q.rotate_vector(FORWARD);
+    let yaw = -forward.y.atan2(forward.x).to_degrees();
+    let pitch = forward.z.clamp(-1f32, 1f32).asin().to_degrees();
+    (yaw, pitch)
+}
+
+// Difference between two angles [°], wrapped to (-180; 180].
+pub fn angle_difference(a: f32, b: f32) -> f32 {
+    let mut d = (a - b) % 360f32;
+    if d > 180f32 {
+        d -= 360f32;
+    } else if d <= -180f32 {
+        d += 360f32;
+    }
+    d
+}
+
+pub struct AbsolutePointer {
+    config: AbsolutePointing,
+    neutral: Option<Vector2<f32>>,
+}
+
+impl AbsolutePointer {
+    pub fn new(config: AbsolutePointing) -> AbsolutePointer {
+        AbsolutePointer {
+            config,
+            neutral: None,
+        }
+    }
+
+    // Forget the neutral pose. The next sample becomes the new neutral pose,
+    // which puts the cursor in the center of the screen.
+    pub fn recenter(&mut self) {
+        self.neutral = None;
+    }
+
+    // Map a deflection [°] onto (-1; 1), applying the gain curve.
+    fn gain(&self, deflection: f32, negative_range: f32, positive_range: f32) -> f32 {
+        let range = if deflection < 0f32 {
+            negative_range
+        } else {
+            positive_range
+        };
+        let ratio = (deflection / range).clamp(-1f32, 1f32);
+        ratio.signum() * ratio.abs().powf(self.config.exponent)
+    }
+
+    // Takes yaw and pitch [°], with positive yaw turning right and positive
+    // pitch looking up, and returns the corresponding normalized position on
+    // the screen, where (0, 0) is the top left corner.
+    pub fn update(&mut self, yaw: f32, pitch: f32) -> Vector2<f32> {
+        let neutral = *self.neutral.get_or_insert(vec2(yaw, pitch));
+
+        let mut dx = angle_difference(yaw, neutral.x);
+        let mut dy = angle_difference(pitch, neutral.y);
+        if self.config.invert_x {
+            dx = -dx;
+        }
+        if self.config.invert_y {
+            dy = -dy;
+        }
+
+        let x = self.gain(dx, self.config.left, self.config.right);
+        let y = self.gain(dy, self.config.down, self.config.up);
+
+        vec2(0.5f32 + 0.5f32 * x, 0.5f32 - 0.5f32 * y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rotation3};
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn linear() -> AbsolutePointing {
+        AbsolutePointing {
+            exponent: 1f32,
+            ..AbsolutePointing::default()
+        }
+    }
+
+    #[test]
+    fn angle_difference_wraps() {
+        assert!(close(angle_difference(30f32, 10f32), 20f32));
+        assert!(close(angle_difference(179f32, -179f32), -2f32));
+        assert!(close(angle_difference(-179f32, 179f32), 2f32));
+        assert!(close(angle_difference(180f32, 0f32), 180f32));
+        assert!(close(angle_difference(0f32, 180f32), 180f32));
+        assert!(close(angle_difference(725f32, 0f32), 5f32));
+    }
+
+    #[test]
+    fn yaw_and_pitch() {
+        // The head level, facing along y of the earth frame.
+        let level = Quaternion::from_angle_x(Deg(-90f32));
+        let (yaw, pitch) = yaw_pitch(level);
+        assert!(close(yaw, -90f32) && close(pitch, 0f32), "{} {}", yaw, pitch);
+
+        // Turning left, i.e. counterclockwise seen from above.
+        let (yaw, pitch) = yaw_pitch(Quaternion::from_angle_z(Deg(30f32)) * level);
+        assert!(close(yaw, -120f32) && close(pitch, 0f32), "{} {}", yaw, pitch);
+
+        // Looking up.
+        let (yaw, pitch) = yaw_pitch(Quaternion::from_angle_x(Deg(20f32)) * level);
+        assert!(close(yaw, -90f32) && close(pitch, 20f32), "{} {}", yaw, pitch);
+    }
+
+    #[test]
+    fn maps_deflection_to_the_screen() {
+        let mut pointer = AbsolutePointer::new(linear());
+        assert_eq!(pointer.update(10f32, 5f32), vec2(0.5f32, 0.5f32));
+        // Halfway to the right edge (20°) and up to the top (12°).
+        assert_eq!(pointer.update(20f32, 5f32), vec2(0.75f32, 0.5f32));
+        assert_eq!(pointer.update(10f32, 11f32), vec2(0.5f32, 0.25f32));
+        // Beyond the range, the cursor stays on the edge.
+        assert_eq!(pointer.update(-30f32, -20f32), vec2(0f32, 1f32));
+    }
+
+    #[test]
+    fn gain_curve() {
+        let mut pointer = AbsolutePointer::new(AbsolutePointing {
+            exponent: 2f32,
+            ..AbsolutePointing::default()
+        });
+        pointer.update(0f32, 0f32);
+        assert_eq!(pointer.update(10f32, 0f32), vec2(0.625f32, 0.5f32));
+        assert_eq!(pointer.update(-10f32, 0f32), vec2(0.375f32, 0.5f32));
+        assert_eq!(pointer.update(20f32, 0f32), vec2(1f32, 0.5f32));
+    }
+
+    #[test]
+    fn inverts() {
+        let mut pointer = AbsolutePointer::new(AbsolutePointing {
+            invert_x: true,
+            invert_y: true,
+            ..linear()
+        });
+        pointer.update(0f32, 0f32);
+        assert_eq!(pointer.update(10f32, 6f32), vec2(0.25f32, 0.75f32));
+    }
+
+    #[test]
+    fn recenter_takes_the_current_pose() {
+        let mut pointer = AbsolutePointer::new(linear());
+        pointer.update(0f32, 0f32);
+        assert_eq!(pointer.update(10f32, 0f32), vec2(0.75f32, 0.5f32));
+
+        pointer.recenter();
+        assert_eq!(pointer.update(10f32, 0f32), vec2(0.5f32, 0.5f32));
+        assert_eq!(pointer.update(20f32, 0f32), vec2(0.75f32, 0.5f32));
+
+        // Across the ±180° seam.
+        pointer.recenter();
+        pointer.update(170f32, 0f32);
+        assert_eq!(pointer.update(-170f32, 0f32), vec2(1f32, 0.5f32));
+    }
+}
@@ -1,5 +1,37 @@
-use std::sync::mpsc;
+use serde::Serialize;
+
+use crate::bus;
+use crate::config::Config;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+// An input together with the instant it happened. For inputs from devices
+// with a clock, this is the timestamp of the device mapped to the host clock,
+// otherwise the instant the input was sent.
+pub struct Event {
+    pub time: Instant,
+    // When the input was sent to the pipeline.
+    pub sent: Instant,
+    pub input: Input,
+}
+
+impl Event {
+    pub fn new(time: Instant, input: Input) -> Event {
+        Event {
+            time,
+            sent: Instant::now(),
+            input,
+        }
+    }
+
+    pub fn now(input: Input) -> Event {
+        Event::new(Instant::now(), input)
+    }
+}
 
 pub enum Input {
     //LinuxTrackHead { yaw: f32, pitch: f32 },
@@ -20,6 +52,11 @@ pub enum Input {
         ly: f32,
         lz: f32,
     },
+    // Which eyes the tracker finds, sent when this changes.
+    TobiiEyeValidity {
+        left: bool,
+        right: bool,
+    },
     TobiiHead {
         px: f32,
         py: f32,
@@ -28,66 +65,549 @@ pub enum Input {
         ry: f32,
         rz: f32,
     },
+    // Angular velocity [° / s] of the head around its yaw (x), pitch (y) and
+    // roll (z) axes.
     Gyro {
         x: f32,
         y: f32,
+        z: f32,
+    },
+    // Orientation of the head as a unit quaternion, fused from acceleration,
+    // angular velocity and magnetic field of the IMU.
+    HeadOrientation {
+        w: f32,
+        x: f32,
+        y: f32,
+        z: f32,
+    },
+    // Position of the eyes, normalized to the track box of the eye tracker.
+    TobiiEyePosition {
+        rx: f32,
+        ry: f32,
+        rz: f32,
+        lx: f32,
+        ly: f32,
+        lz: f32,
+    },
+    // Position of the eyes, normalized such that (0.5, 0.5, 0.5) is the
+    // optimal position in front of the eye tracker.
+    TobiiUserPosition {
+        rx: f32,
+        ry: f32,
+        rz: f32,
+        lx: f32,
+        ly: f32,
+        lz: f32,
+    },
+    // Health of an input source, reported by its supervisor.
+    SourceHealth {
+        source: &'static str,
+        health: Health,
+        restarts: u32,
+    },
+    // Whether the eye tracker is connected. While it is not, there is no gaze.
+    TobiiConnection { connected: bool },
+    // Streams the eye tracker was subscribed to. Sent after connecting.
+    TobiiStreams(TrackerStreams),
+    // Whether a user is in front of the eye tracker.
+    TobiiPresence {
+        present: bool,
     },
+    TobiiNotification(TrackerNotification),
+    // Take the current head pose as the new neutral pose for absolute
+    // pointing.
+    Recenter,
+    // The configuration file changed.
+    Config(Box<Config>),
     Shutdown,
 }
 
+// How the event bus treats an input, see `bus`.
+pub enum Class {
+    // Rare inputs that change state or control the pipeline.
+    Control,
+    // Samples superseded by the next one of the same kind.
+    Latest,
+    // Samples that must all be processed.
+    Queued,
+}
+
+impl Input {
+    pub fn class(&self) -> Class {
+        match self {
+            Input::HeadAngle { .. }
+            | Input::TobiiGaze { .. }
+            | Input::TobiiGazeOrigin { .. }
+            | Input::TobiiHead { .. }
+            | Input::HeadOrientation { .. }
+            | Input::TobiiEyePosition { .. }
+            | Input::TobiiUserPosition { .. } => Class::Latest,
+            // Angular velocity is accumulated into relative movement.
+            Input::Gyro { .. } => Class::Queued,
+            // Blinks are told apart by how long each eye was closed.
+            Input::TobiiEyeValidity { .. } => Class::Queued,
+            Input::SourceHealth { .. }
+            | Input::TobiiConnection { .. }
+            | Input::TobiiStreams(_)
+            | Input::TobiiPresence { .. }
+            | Input::TobiiNotification(_)
+            | Input::Recenter
+            | Input::Config(_)
+            | Input::Shutdown => Class::Control,
+        }
+    }
+}
+
+// Optional streams of the eye tracker. Gaze point is always available.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct TrackerStreams {
+    pub gaze_origin: bool,
+    pub eye_position: bool,
+    pub presence: bool,
+    pub head_pose: bool,
+    pub user_position_guide: bool,
+}
+
+// Corners of the display [mm] in the coordinate system of the eye tracker.
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayArea {
+    pub top_left: [f32; 3],
+    pub top_right: [f32; 3],
+    pub bottom_left: [f32; 3],
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+impl DisplayArea {
+    pub fn width(&self) -> f32 {
+        distance(self.top_left, self.top_right)
+    }
+
+    pub fn height(&self) -> f32 {
+        distance(self.top_left, self.bottom_left)
+    }
+}
+
+// Changes of the state of the eye tracker. Some are also sent once after
+// connecting, to report the initial state.
+#[derive(Clone, Debug)]
+pub enum TrackerNotification {
+    CalibrationStateChanged { active: bool },
+    CalibrationIdChanged { id: u32 },
+    DisplayAreaChanged(DisplayArea),
+    // Output frequency [Hz] of the eye tracker.
+    FramerateChanged { frequency: f32 },
+    PowerSaveStateChanged { active: bool },
+    DevicePausedStateChanged { paused: bool },
+    FaultsChanged { faults: String },
+    WarningsChanged { warnings: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum InputAction {
-    // Pause,
-    // Resume,
+    // Stop sending samples until resumed, but stay connected to the device.
+    // Control inputs, e.g. presence, are still sent.
+    Pause,
+    Resume,
     Shutdown,
 }
 
+// What to do when a source exits or panics without being asked to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Restart {
+    Always,
+    // Restart after up to this many failures in a row. A run that lasted
+    // `STABLE_RUN` ends the row.
+    Times(u32),
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Running,
+    // The source exited and will be started again.
+    Restarting,
+    // The source exited and its restart policy does not allow to start it
+    // again.
+    Failed,
+    // The source exited after being asked to shut down.
+    Stopped,
+}
+
+// Delay before a source that exited is started again.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// How long a source has to run before it counts as recovered.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+struct Timing {
+    restart_delay: Duration,
+    stable_run: Duration,
+}
+
+// How long to wait for a source to exit after asking it to shut down. Sources
+// that do not react in time are left behind.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Supervision {
+    Action(InputAction),
+    Exited { panicked: bool },
+}
+
 struct InputThread {
-    inbox: mpsc::Sender<InputAction>,
+    name: &'static str,
+    inbox: mpsc::Sender<Supervision>,
     handle: Option<thread::JoinHandle<()>>,
 }
 
+// Runs a source on its own thread until it exits, and restarts it according
+// to `restart`. Actions are forwarded to the current run of the source, and
+// a restarted source is paused again if it was.
+fn supervise<F>(
+    name: &'static str,
+    restart: Restart,
+    timing: Timing,
+    f: Arc<F>,
+    output: bus::Sender,
+    events: mpsc::Sender<Supervision>,
+    inbox: mpsc::Receiver<Supervision>,
+) where
+    F: Fn(bus::Sender, mpsc::Receiver<InputAction>) -> (),
+    F: Send + Sync + 'static,
+{
+    let report = |health: Health, restarts: u32| {
+        // Nobody may be listening any more during shutdown.
+        let _ = output.send(Event::now(Input::SourceHealth {
+            source: name,
+            health,
+            restarts,
+        }));
+    };
+
+    let mut restarts = 0;
+    // Failures since the source last ran for `stable_run`.
+    let mut failures = 0;
+    let mut paused = false;
+    loop {
+        let started = Instant::now();
+        let (tx, rx) = mpsc::channel::<InputAction>();
+        if paused {
+            let _ = tx.send(InputAction::Pause);
+        }
+        let run = {
+            let f = f.clone();
+            let output = output.clone();
+            let events = events.clone();
+            thread::spawn(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| (*f)(output, rx)));
+                let _ = events.send(Supervision::Exited {
+                    panicked: result.is_err(),
+                });
+            })
+        };
+        report(Health::Running, restarts);
+
+        let mut shutdown = false;
+        let panicked = loop {
+            let event = if shutdown {
+                match inbox.recv_timeout(SHUTDOWN_TIMEOUT) {
+                    Ok(event) => event,
+                    Err(_) => {
+                        println!("Input {} did not shut down, leaving it behind.", name);
+                        return;
+                    }
+                }
+            } else {
+                match inbox.recv() {
+                    Ok(event) => event,
+                    Err(_) => return,
+                }
+            };
+            match event {
+                Supervision::Action(action) => {
+                    match action {
+                        InputAction::Pause => paused = true,
+                        InputAction::Resume => paused = false,
+                        InputAction::Shutdown => shutdown = true,
+                    }
+                    // The source may have stopped listening already.
+                    let _ = tx.send(action);
+                }
+                Supervision::Exited { panicked } => break panicked,
+            }
+        };
+        let _ = run.join();
+
+        if shutdown {
+            report(Health::Stopped, restarts);
+            return;
+        }
+
+        if panicked {
+            println!("Input {} panicked.", name);
+        } else {
+            println!("Input {} exited.", name);
+        }
+
+        if started.elapsed() >= timing.stable_run {
+            failures = 0;
+        }
+        let allowed = match restart {
+            Restart::Always => true,
+            Restart::Times(n) => failures < n,
+            Restart::Never => false,
+        };
+        if !allowed {
+            report(Health::Failed, restarts);
+            return;
+        }
+        restarts += 1;
+        failures += 1;
+        report(Health::Restarting, restarts);
+
+        // Wait before restarting, but stay responsive to actions.
+        let deadline = Instant::now() + timing.restart_delay;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match inbox.recv_timeout(timeout) {
+                Ok(Supervision::Action(InputAction::Shutdown))
+                | Err(RecvTimeoutError::Disconnected) => {
+                    report(Health::Stopped, restarts);
+                    return;
+                }
+                Ok(Supervision::Action(InputAction::Pause)) => paused = true,
+                Ok(Supervision::Action(InputAction::Resume)) => paused = false,
+                Ok(Supervision::Exited { .. }) => (),
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+        }
+    }
+}
+
 pub struct InputPool {
     threads: Vec<InputThread>,
-    sender: mpsc::SyncSender<Input>,
+    sender: bus::Sender,
+    timing: Timing,
 }
 
 impl InputPool {
-    pub fn new() -> (InputPool, mpsc::Receiver<Input>) {
-        let (tx, rx) = mpsc::sync_channel::<Input>(0); // TODO choose best constant
+    pub fn new() -> (InputPool, bus::Receiver) {
+        let rx = bus::new();
         let pool = InputPool {
             threads: vec![],
-            sender: tx,
+            sender: rx.sender("pool"),
+            timing: Timing {
+                restart_delay: RESTART_DELAY,
+                stable_run: STABLE_RUN,
+            },
         };
         (pool, rx)
     }
 
-    pub fn spawn<F>(&mut self, f: F)
+    // Starts the source `f` under supervision. It is called again whenever it
+    // exits or panics, as far as `restart` allows.
+    pub fn spawn<F>(&mut self, name: &'static str, restart: Restart, f: F)
     where
-        F: FnOnce(mpsc::SyncSender<Input>, mpsc::Receiver<InputAction>) -> (),
-        F: Send + 'static,
+        F: Fn(bus::Sender, mpsc::Receiver<InputAction>) -> (),
+        F: Send + Sync + 'static,
     {
-        let (tx, rx) = mpsc::channel::<InputAction>();
-        let sender = self.sender.clone();
-        let handle = thread::spawn(|| f(sender, rx));
+        let (tx, rx) = mpsc::channel::<Supervision>();
+        let sender = self.sender.sender(name);
+        let events = tx.clone();
+        let f = Arc::new(f);
+        let timing = self.timing;
+        let handle =
+            thread::spawn(move || supervise(name, restart, timing, f, sender, events, rx));
         self.threads.push(InputThread {
+            name,
             inbox: tx,
             handle: Some(handle),
         });
     }
+
+    // Sends `action` to the source `name`, if it is still supervised.
+    pub fn send(&self, name: &str, action: InputAction) {
+        for thread in self.threads.iter().filter(|t| t.name == name) {
+            let _ = thread.inbox.send(Supervision::Action(action));
+        }
+    }
 }
 
 impl Drop for InputPool {
     fn drop(&mut self) {
+        // Supervisors of sources that failed for good are gone already.
         for thread in &self.threads {
-            thread.inbox.send(InputAction::Shutdown).unwrap();
+            let _ = thread
+                .inbox
+                .send(Supervision::Action(InputAction::Shutdown));
         }
 
         for thread in &mut self.threads {
             if let Some(handle) = thread.handle.take() {
-                handle.join().unwrap();
+                if handle.join().is_err() {
+                    println!("Input supervisor panicked.");
+                }
             }
         }
 
-        self.sender.send(Input::Shutdown).unwrap();
+        let _ = self.sender.send(Event::now(Input::Shutdown));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn pool() -> (InputPool, bus::Receiver) {
+        let (mut pool, rx) = InputPool::new();
+        pool.timing = Timing {
+            restart_delay: Duration::from_millis(10),
+            stable_run: Duration::from_millis(200),
+        };
+        (pool, rx)
+    }
+
+    // Health reports of `source`, up to `last`.
+    fn reports(rx: &bus::Receiver, source: &str, last: Health) -> Vec<(Health, u32)> {
+        let mut reports = vec![];
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(5)) {
+            if let Input::SourceHealth {
+                source: s,
+                health,
+                restarts,
+            } = event.input
+            {
+                if s == source {
+                    reports.push((health, restarts));
+                    if health == last {
+                        break;
+                    }
+                }
+            }
+        }
+        reports
+    }
+
+    // A source that counts its runs and exits right away.
+    fn failing(runs: &Arc<AtomicUsize>) -> impl Fn(bus::Sender, mpsc::Receiver<InputAction>) {
+        let runs = runs.clone();
+        move |_, _| {
+            runs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // A source that runs until it is shut down.
+    fn waiting(_: bus::Sender, inbox: mpsc::Receiver<InputAction>) {
+        while let Ok(action) = inbox.recv() {
+            if action == InputAction::Shutdown {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn never_restarts() {
+        let (mut pool, rx) = pool();
+        let runs = Arc::new(AtomicUsize::new(0));
+        pool.spawn("source", Restart::Never, failing(&runs));
+        assert_eq!(
+            reports(&rx, "source", Health::Failed),
+            vec![(Health::Running, 0), (Health::Failed, 0)]
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn restarts_a_number_of_times() {
+        let (mut pool, rx) = pool();
+        let runs = Arc::new(AtomicUsize::new(0));
+        pool.spawn("source", Restart::Times(2), failing(&runs));
+        assert_eq!(
+            reports(&rx, "source", Health::Failed),
+            vec![
+                (Health::Running, 0),
+                (Health::Restarting, 1),
+                (Health::Running, 1),
+                (Health::Restarting, 2),
+                (Health::Running, 2),
+                (Health::Failed, 2),
+            ]
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn always_restarts() {
+        let (mut pool, rx) = pool();
+        let runs = Arc::new(AtomicUsize::new(0));
+        pool.spawn("source", Restart::Always, failing(&runs));
+        let mut restarting = 0;
+        while restarting < 5 {
+            let reports = reports(&rx, "source", Health::Restarting);
+            assert_eq!(reports.last().unwrap().0, Health::Restarting);
+            restarting += 1;
+        }
+        drop(pool);
+        assert!(runs.load(Ordering::SeqCst) >= 5);
+    }
+
+    #[test]
+    fn stable_runs_reset_failures() {
+        let (mut pool, rx) = pool();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        // Fails after running longer than `stable_run` every time.
+        pool.spawn("source", Restart::Times(1), move |_, inbox| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let _ = inbox.recv_timeout(Duration::from_millis(250));
+        });
+        for _ in 0..3 {
+            let reports = reports(&rx, "source", Health::Restarting);
+            assert_eq!(reports.last().unwrap().0, Health::Restarting);
+        }
+        assert!(runs.load(Ordering::SeqCst) >= 3);
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        let (mut pool, rx) = pool();
+        pool.spawn("source", Restart::Times(1), |_, _| panic!("broken source"));
+        assert_eq!(
+            reports(&rx, "source", Health::Failed),
+            vec![
+                (Health::Running, 0),
+                (Health::Restarting, 1),
+                (Health::Running, 1),
+                (Health::Failed, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn shuts_down_after_a_source_failed() {
+        let (mut pool, rx) = pool();
+        let runs = Arc::new(AtomicUsize::new(0));
+        pool.spawn("failing", Restart::Never, failing(&runs));
+        pool.spawn("waiting", Restart::Never, waiting);
+        assert_eq!(
+            reports(&rx, "failing", Health::Failed).last(),
+            Some(&(Health::Failed, 0))
+        );
+
+        let start = Instant::now();
+        drop(pool);
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+        assert_eq!(
+            reports(&rx, "waiting", Health::Stopped).last(),
+            Some(&(Health::Stopped, 0))
+        );
+        let shutdown = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(shutdown.input, Input::Shutdown));
     }
 }
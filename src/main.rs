@@ -1,44 +1,73 @@
 extern crate cgmath;
-extern crate enigo;
 extern crate tobii_sys;
-extern crate tokio;
 
+mod acceleration;
+mod ahrs;
+mod blinks;
+mod bus;
+mod clock;
+mod config;
+mod dwell;
+mod gaze_scroll;
+mod gestures;
 mod gyro_input;
+mod head_pointer;
 mod inputs;
+mod mount_calibration;
+mod output;
+mod overlay;
+mod presence;
+mod reload;
+mod screen_switch;
+mod screens;
+mod snapping;
+mod stats;
+mod status;
 mod tobii_input;
+mod zoom;
 
 use cgmath::prelude::MetricSpace;
-use cgmath::{vec2, vec3, Vector2};
-use enigo::{Enigo, MouseControllable};
+use cgmath::{vec2, vec3, Quaternion, Vector2};
 
 use std::cmp::{max, min};
+use std::env;
 use std::mem;
-use std::sync::mpsc::Receiver;
+use std::process;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use inputs::{Input, InputPool};
-use std::process::Command;
+use acceleration::Accelerator;
+use blinks::Detector as BlinkDetector;
+use config::{Config, HeadSource, PointingMode};
+use dwell::Dwell;
+use gaze_scroll::GazeScroll;
+use gestures::{Action, Recognizer};
+use head_pointer::AbsolutePointer;
+use inputs::{
+    Event, Input, InputAction, InputPool, Restart, TrackerNotification, TrackerStreams,
+};
+use output::{Button, Output, Subpixel};
+use presence::Presence;
+use screen_switch::Switcher;
+use screens::{Layout, Screen};
+use snapping::Snapper;
+use stats::{SharedStats, Stats, TimedOutput};
+use status::SharedStatus;
+use zoom::Magnifier;
 
-// TODO: Do not hardcode display height and width, but
-// make it configurable or auto-detect.
-
-const display_laptop_x: f32 = 1920f32;
-const display_laptop_y: f32 = 1080f32;
-const display_dell_x: f32 = 2560f32;
-const display_dell_y: f32 = 1440f32;
+const distance_to_center_max: f32 = 0.7071067811865476f32;
 
-const offset_laptop_x: f32 = display_dell_x / 2f32 - display_laptop_x / 2f32;
-const offset_laptop_y: f32 = display_dell_y;
-const offset_dell_x: f32 = 0f32;
-const offset_dell_y: f32 = 0f32;
+// Gaze is considered stale if no sample arrived for this many intervals of the
+// output frequency of the eye tracker.
+const gaze_stale_frames: u32 = 10;
 
-const display_x: f32 = display_dell_x;
-const display_y: f32 = display_dell_y;
-const offset_x: f32 = offset_dell_x;
-const offset_y: f32 = offset_dell_y;
+// How often drop counters of the event bus are copied to the status.
+const queue_report_interval: Duration = Duration::from_secs(1);
 
-const distance_to_center_max: f32 = 0.7071067811865476f32;
+// How often dwell clicking looks at the cursor, also while there is no input.
+const dwell_interval: Duration = Duration::from_millis(50);
 
 fn fmax(a: f32, b: f32) -> f32 {
     if b.is_nan() || b <= a {
@@ -89,41 +118,97 @@ fn is_warp(cx: i32, cy: i32, x: i32, y: i32) -> bool {
 }
 */
 
-fn current_location() -> (i32, i32) {
-    // TODO(lorenz.leutgeb): Look for a faster version to obtain the current
-    // cursor position. Spawning xdotool adds a dependency and is quite heavy.
-    let mut current = String::from_utf8(
-        Command::new("xdotool")
-            .arg("getmouselocation")
-            .arg("--shell")
-            .output()
-            .unwrap()
-            .stdout,
-    )
-    .unwrap()
-    .split("\n")
-    .take(2)
-    .map(|s| -> i32 { s[2..].parse::<i32>().unwrap() })
-    .collect::<Vec<i32>>();
-
-    let y = current.pop().unwrap();
-    let x = current.pop().unwrap();
-    (x, y)
+fn notify(status: &SharedStatus, notification: TrackerNotification) {
+    let mut status = status.lock().unwrap();
+    match notification {
+        TrackerNotification::CalibrationStateChanged { active } => {
+            status.calibration_active = Some(active);
+            status.event(format!("Calibration active: {}", active));
+        }
+        TrackerNotification::CalibrationIdChanged { id } => {
+            status.calibration_id = Some(id);
+            status.event(format!("Calibration changed: {}", id));
+        }
+        TrackerNotification::DisplayAreaChanged(area) => {
+            status.display_area = Some([area.width(), area.height()]);
+            status.event(format!(
+                "Display area changed: {:.0}mm x {:.0}mm",
+                area.width(),
+                area.height()
+            ));
+        }
+        TrackerNotification::FramerateChanged { frequency } => {
+            status.tracker_frequency = Some(frequency);
+            status.event(format!("Output frequency changed: {}Hz", frequency));
+        }
+        TrackerNotification::PowerSaveStateChanged { active } => {
+            status.tracker_power_save = Some(active);
+            status.event(format!("Power save active: {}", active));
+        }
+        TrackerNotification::DevicePausedStateChanged { paused } => {
+            status.tracker_paused = Some(paused);
+            status.event(format!("Tracker paused: {}", paused));
+        }
+        TrackerNotification::FaultsChanged { faults } => {
+            status.event(format!("Tracker faults: {}", faults));
+        }
+        TrackerNotification::WarningsChanged { warnings } => {
+            status.event(format!("Tracker warnings: {}", warnings));
+        }
+    }
 }
 
-fn move_cursor_relative(x: i32, y: i32) {
-    // TODO(lorenz.leutgeb): Look for a native solution to move the cursor.
-    // Spawning xdotool adds a dependency and is quite heavy.
-    Command::new("xdotool")
-        .arg("mousemove_relative")
-        .arg("--")
-        .arg((x).to_string())
-        .arg((y).to_string())
-        .status();
+// Runs an action of a gesture or blink. Clicks only happen while the user
+// is present and in control.
+#[allow(clippy::too_many_arguments)]
+fn perform(
+    action: Action,
+    gaze: Vector2<f32>,
+    output: &mut Magnifier<TimedOutput>,
+    present: bool,
+    controlling: &mut bool,
+    scrolling: &mut bool,
+    gaze_scroll: &mut GazeScroll,
+    absolute_pointer: &mut AbsolutePointer,
+) {
+    match action {
+        Action::None => (),
+        Action::LeftClick if present && *controlling => output.click(Button::Left),
+        Action::RightClick if present && *controlling => output.click(Button::Right),
+        Action::LeftClick | Action::RightClick => (),
+        Action::ScrollMode => *scrolling = !*scrolling,
+        Action::GazeScroll => gaze_scroll.toggle(),
+        Action::Toggle => {
+            *controlling = !*controlling;
+            println!("Cursor control: {}", controlling);
+        }
+        Action::Recenter => absolute_pointer.recenter(),
+        Action::Zoom => output.toggle(gaze),
+    }
 }
 
-fn run_pipeline(rx: Receiver<Input>) {
+// Pauses or resumes the source `name` when `idle` changes.
+fn pause(pool: &InputPool, name: &str, idle: bool, paused: &mut bool) {
+    if idle != *paused {
+        *paused = idle;
+        let action = if idle {
+            InputAction::Pause
+        } else {
+            InputAction::Resume
+        };
+        pool.send(name, action);
+    }
+}
+
+fn run_pipeline(
+    pool: &InputPool,
+    rx: bus::Receiver,
+    mut config: Config,
+    status: SharedStatus,
+    stats: SharedStats,
+) {
     let mut raw_head_angular_velocity: Vector2<f32> = vec2(0.0, 0.0);
+    let mut raw_head_pose: Option<Vector2<f32>> = None; // [°] yaw, pitch
     let mut raw_gaze: Vector2<f32> = vec2(0.0, 0.0);
 
     let mut last_head_move = Instant::now();
@@ -132,18 +217,136 @@ fn run_pipeline(rx: Receiver<Input>) {
     let mut anchor: Vector2<f32> = vec2(0.0, 0.0); // [px]
     let mut px_gaze: Vector2<f32>; // [px]
 
-    let mut enigo = Enigo::new();
+    // Gaze is mapped onto the monitor with the eye tracker, head movement
+    // may take the cursor anywhere on the desktop.
+    // Both are detected again once the display area of the eye tracker is
+    // known.
+    let mut layout = Layout::detect(&config.screens, None);
+    let mut screen = layout.tracker().clone();
+    println!(
+        "Eye tracker on monitor {} ({}x{}+{}+{})",
+        screen.name, screen.width, screen.height, screen.x, screen.y
+    );
+
+    let mut output = Magnifier::new(
+        TimedOutput::new(output::create(config.output.backend)),
+        config.zoom.clone(),
+    );
+    let mut presence = Presence::new(config.presence.clone());
+    // Whether the eye tracker finds the user. While they look at another
+    // monitor, it will not, but they are still there.
+    let mut user_present = true;
+    let mut switcher = Switcher::new(config.screen_switch.clone(), &layout);
+    // Whether to warp to the gaze right away, after looking back at the
+    // monitor with the eye tracker.
+    let mut rejump = false;
+    let mut dwell = Dwell::new(config.dwell.clone());
+    let mut last_dwell = Instant::now();
+    let recv_timeout = if dwell.is_enabled() {
+        dwell_interval
+    } else {
+        Duration::from_secs(1)
+    };
+
+    let mut last_gaze: Option<Instant> = None;
+    let mut gaze_interval = Duration::from_millis(11); // 90Hz until told otherwise
+    let mut gaze_stale = true;
 
     let mut distance_to_screen = 0.50f32; // [m]
 
+    let mut accelerator = Accelerator::new(config.pointing.relative.acceleration.clone());
+
+    let mut streams = TrackerStreams::default();
+    let mut head_source = config.pointing.absolute.source;
+    let mut absolute_pointer = AbsolutePointer::new(config.pointing.absolute.clone());
+    let snapper = Snapper::new(config.snapping.clone());
+
+    let mut gestures = Recognizer::new(config.gestures.clone());
+    // Whether the cursor is moved, toggled by gestures.
+    let mut controlling = true;
+    // Whether head motion scrolls instead of moving the cursor.
+    let mut scrolling = false;
+    // Whether the sources were asked to stop sending samples.
+    let mut gyro_paused = false;
+    let mut tobii_paused = false;
+    let mut scroll = Subpixel::new();
+    let mut gaze_scroll = GazeScroll::new(
+        config.gaze_scroll.clone(),
+        screen.origin(),
+        screen.size(),
+    );
+    let mut last_gyro: Option<Instant> = None;
+
+    let mut blinks = BlinkDetector::new(config.blinks.clone(), config.tobii.eye);
+    // Position [px] of the cursor before the eyes closed.
+    let mut blink_anchor: Vector2<f32> = vec2(0.0, 0.0);
+    // Cursor position [px] before the swings of a possible gesture.
+    let mut gesture_anchor: Vector2<f32> = vec2(0.0, 0.0);
+
+    // Instant the previous input was received, its timestamp and whether it
+    // was gaze, for measuring the time spent on it.
+    let mut processing: Option<(Instant, Instant, bool)> = None;
+
+    let mut last_queue_report = Instant::now();
+
     loop {
+        if last_queue_report.elapsed() >= queue_report_interval {
+            last_queue_report = Instant::now();
+            status.lock().unwrap().queues = rx.stats();
+        }
+
+        if let Some((received, time, gaze)) = processing.take() {
+            let done = Instant::now();
+            let mut stats = stats.lock().unwrap();
+            stats.record("processing", done.duration_since(received));
+            if let Some(elapsed) = output.inner.elapsed.take() {
+                stats.record("output", elapsed);
+                if gaze {
+                    stats.record("gaze to cursor", done.saturating_duration_since(time));
+                }
+            }
+        }
+
+        if dwell.is_enabled() && last_dwell.elapsed() >= dwell_interval {
+            last_dwell = Instant::now();
+            if presence.is_present() && controlling {
+                dwell.update(last_dwell, anchor, &mut output);
+            } else {
+                dwell.cancel();
+            }
+        }
+
         // update input state =========================
         let mut tick_gaze = false;
         let mut tick_head = false;
-        match rx.recv().unwrap() {
+        let Event { time, sent, input } = match rx.recv_timeout(recv_timeout) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                presence.poll(Instant::now());
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let received = Instant::now();
+        let device = match input {
+            Input::TobiiGaze { .. } => Some("gaze device"),
+            Input::Gyro { .. } => Some("head device"),
+            _ => None,
+        };
+        {
+            let mut stats = stats.lock().unwrap();
+            stats.record("queue", received.saturating_duration_since(sent));
+            if let Some(stage) = device {
+                stats.record(stage, sent.saturating_duration_since(time));
+            }
+        }
+        processing = Some((received, time, device == Some("gaze device")));
+        match input {
             Input::TobiiGaze { x, y } => {
                 raw_gaze = vec2(x, y);
                 tick_gaze = true;
+                last_gaze = Some(time);
             }
             Input::TobiiGazeOrigin {
                 rx,
@@ -165,55 +368,332 @@ fn run_pipeline(rx: Receiver<Input>) {
                 distance_to_screen = (distance_to_left_eye + distance_to_right_eye) / 2000f32;
                 //println!("{0:8.3}", distance_to_screen);
             }
-            Input::Gyro { x, y } => {
+            Input::Gyro { x, y, z } => {
                 raw_head_angular_velocity = vec2(x, y);
                 tick_head = true;
+                let dt = last_gyro
+                    .replace(time)
+                    .map(|last| time.saturating_duration_since(last).as_secs_f32());
+
+                if !gestures.is_pending() {
+                    gesture_anchor = anchor;
+                }
+                if let Some(gesture) = gestures.update(time, vec3(x, y, z)) {
+                    let action = config.gestures.actions.get(gesture);
+                    status
+                        .lock()
+                        .unwrap()
+                        .event(format!("Gesture {:?}: {:?}", gesture, action));
+                    // The swings of the gesture moved the cursor as well, so
+                    // act where it was before.
+                    tick_head = false;
+                    if controlling && presence.is_present() {
+                        anchor = gesture_anchor;
+                        output.move_to(anchor.x as i32, anchor.y as i32);
+                    }
+                    perform(
+                        action,
+                        denormalize(&screen, raw_gaze),
+                        &mut output,
+                        presence.is_present(),
+                        &mut controlling,
+                        &mut scrolling,
+                        &mut gaze_scroll,
+                        &mut absolute_pointer,
+                    );
+                }
+
+                if scrolling {
+                    // Head motion is used up by scrolling.
+                    tick_head = false;
+                    let dt = dt.filter(|_| controlling && presence.is_present());
+                    if let Some(dt) = dt {
+                        let angle = config.gestures.scroll_angle;
+                        let (dx, dy) = scroll.split(x * dt / angle, y * dt / angle);
+                        if dx != 0 || dy != 0 {
+                            output.scroll(dx, dy);
+                        }
+                    }
+                }
+            }
+            Input::TobiiEyeValidity { left, right } => {
+                if !blinks.is_closed() {
+                    blink_anchor = anchor;
+                }
+                if let Some(blink) = blinks.update(time, left, right) {
+                    let action = config.blinks.actions.get(blink);
+                    status
+                        .lock()
+                        .unwrap()
+                        .event(format!("Blink {:?}: {:?}", blink, action));
+                    // Gaze tends to drift while the eyelids close, so click
+                    // where the cursor was before.
+                    let click = action == Action::LeftClick || action == Action::RightClick;
+                    if click && controlling && presence.is_present() {
+                        anchor = blink_anchor;
+                        output.move_to(anchor.x as i32, anchor.y as i32);
+                    }
+                    perform(
+                        action,
+                        denormalize(&screen, raw_gaze),
+                        &mut output,
+                        presence.is_present(),
+                        &mut controlling,
+                        &mut scrolling,
+                        &mut gaze_scroll,
+                        &mut absolute_pointer,
+                    );
+                }
+            }
+            Input::HeadAngle { yaw, pitch, .. } => {
+                if head_source == HeadSource::Angle {
+                    raw_head_pose = Some(vec2(yaw, pitch));
+                }
+            }
+            Input::HeadOrientation { w, x, y, z } => {
+                if head_source == HeadSource::Fused {
+                    let (yaw, pitch) = head_pointer::yaw_pitch(Quaternion::new(w, x, y, z));
+                    raw_head_pose = Some(vec2(yaw, pitch));
+                }
+            }
+            Input::TobiiHead { rx, ry, .. } => {
+                // Rotation reported by Tobii is in radians, x is pitch and y is yaw.
+                if head_source == HeadSource::Tobii {
+                    raw_head_pose = Some(vec2(ry.to_degrees(), rx.to_degrees()));
+                }
+            }
+            Input::SourceHealth {
+                source,
+                health,
+                restarts,
+            } => {
+                let mut status = status.lock().unwrap();
+                status.sources.insert(source, health);
+                status.event(format!(
+                    "Input {}: {:?} ({} restarts)",
+                    source, health, restarts
+                ));
+            }
+            Input::TobiiConnection { connected } => {
+                let mut status = status.lock().unwrap();
+                status.tracker_connected = Some(connected);
+                if connected {
+                    status.event("Eye tracker connected".to_string());
+                } else {
+                    status.event("Eye tracker disconnected".to_string());
+                    // Mark gaze as stale right away.
+                    last_gaze = None;
+                    blinks.reset();
+                }
+            }
+            Input::TobiiStreams(available) => {
+                streams = available;
+                if head_source == HeadSource::Tobii && !streams.head_pose {
+                    println!("Tracker does not support head pose, using fused IMU orientation.");
+                    head_source = HeadSource::Fused;
+                }
+                status.lock().unwrap().tracker_streams = Some(streams);
+            }
+            Input::TobiiUserPosition {
+                rx,
+                ry,
+                rz,
+                lx,
+                ly,
+                lz,
+            } => {
+                status.lock().unwrap().user_position =
+                    Some([(rx + lx) / 2f32, (ry + ly) / 2f32, (rz + lz) / 2f32]);
+            }
+            // The track box is centered in front of the tracker as well, so
+            // eye position is a good enough guide if there is no other.
+            Input::TobiiEyePosition {
+                rx,
+                ry,
+                rz,
+                lx,
+                ly,
+                lz,
+            } => {
+                if !streams.user_position_guide {
+                    status.lock().unwrap().user_position =
+                        Some([(rx + lx) / 2f32, (ry + ly) / 2f32, (rz + lz) / 2f32]);
+                }
+            }
+            Input::TobiiNotification(notification) => {
+                match notification {
+                    TrackerNotification::FramerateChanged { frequency } if frequency > 0f32 => {
+                        gaze_interval = Duration::from_micros((1e6f32 / frequency) as u64);
+                    }
+                    TrackerNotification::DisplayAreaChanged(ref area) => {
+                        // The eye tracker may have been set up for another
+                        // monitor, or the monitors may have changed.
+                        let size = [area.width(), area.height()];
+                        layout = Layout::detect(&config.screens, Some(size));
+                        let tracker = layout.tracker();
+                        if *tracker != screen {
+                            screen = tracker.clone();
+                            status.lock().unwrap().event(format!(
+                                "Eye tracker on monitor {} ({}x{}+{}+{})",
+                                screen.name, screen.width, screen.height, screen.x, screen.y
+                            ));
+                        }
+                        switcher = Switcher::new(config.screen_switch.clone(), &layout);
+                        gaze_scroll.resize(screen.origin(), screen.size());
+                        anchor = layout.clamp(anchor);
+                    }
+                    _ => {}
+                }
+                notify(&status, notification);
+            }
+            Input::TobiiPresence { present } => {
+                status.lock().unwrap().present = Some(present);
+                user_present = present;
+            }
+            Input::Recenter => absolute_pointer.recenter(),
+            Input::Config(new) => {
+                // Only tuning is applied, the rest would mean setting up
+                // devices and windows again.
+                let restart = config.restart_needed(&new);
+                let source = config.pointing.absolute.source;
+                config.pointing = new.pointing;
+                config.pointing.absolute.source = source;
+                config.gestures = new.gestures;
+                config.blinks = new.blinks;
+                accelerator = Accelerator::new(config.pointing.relative.acceleration.clone());
+                absolute_pointer = AbsolutePointer::new(config.pointing.absolute.clone());
+                gestures = Recognizer::new(config.gestures.clone());
+                blinks = BlinkDetector::new(config.blinks.clone(), config.tobii.eye);
+                let event = if restart.is_empty() {
+                    "Configuration reloaded".to_string()
+                } else {
+                    format!(
+                        "Configuration reloaded, restart to apply changes to {}",
+                        restart.join(", ")
+                    )
+                };
+                status.lock().unwrap().event(event);
             }
             Input::Shutdown => break,
-            _ => {
-                panic!("got some input that will not be handled");
+        }
+
+        // A click through the lens moved the cursor, carry on from there.
+        if let Some(warped) = output.warped.take() {
+            anchor = warped;
+        }
+
+        let now = Instant::now();
+
+        let stale = match last_gaze {
+            Some(last) => now.saturating_duration_since(last) > gaze_interval * gaze_stale_frames,
+            None => true,
+        };
+        if stale != gaze_stale {
+            gaze_stale = stale;
+            status.lock().unwrap().gaze_stale = stale;
+        }
+
+        let on_screen = |p: f32| p >= 0f32 && p <= 1f32;
+        let looking = !gaze_stale && on_screen(raw_gaze.x) && on_screen(raw_gaze.y);
+        let switched = switcher.update(raw_head_pose, looking);
+        if let Some(restored) = presence.update(user_present || switcher.is_away(), &mut output) {
+            anchor = restored;
+        }
+        if let Some(index) = switched {
+            let target = &layout.screens[index];
+            status
+                .lock()
+                .unwrap()
+                .event(format!("Looking at monitor {}", target.name));
+            if !switcher.is_away() {
+                rejump = true;
+            } else if controlling && !target.contains(anchor) {
+                anchor = target.center();
+                output.move_to(anchor.x as i32, anchor.y as i32);
             }
         }
 
-        let tick = Instant::now();
+        presence.poll(now);
+        let idle = !presence.is_present() || !controlling;
 
-        if tick_head {
-            // Rotate to account for mounting offset.
-            raw_head_angular_velocity = rotate(raw_head_angular_velocity, 13f32);
+        // Sources that are not needed stop sending samples. Only the one
+        // that can give back control keeps going while it is taken away.
+        let gyro_idle = !presence.is_present()
+            || !(controlling || config.gestures.triggers(Action::Toggle));
+        let tobii_idle =
+            !presence.is_present() || !(controlling || config.blinks.triggers(Action::Toggle));
+        pause(pool, "gyro", gyro_idle, &mut gyro_paused);
+        pause(pool, "tobii", tobii_idle, &mut tobii_paused);
 
-            let mut angular = vec2(
-                (raw_head_angular_velocity.x * distance_to_screen * 1.7) as i32,
-                (raw_head_angular_velocity.y * distance_to_screen) as i32,
-            );
+        if idle {
+            continue;
+        }
+
+        if tick_gaze && !output.is_open() && !switcher.is_away() {
+            let px = denormalize(&screen, raw_gaze);
+            let (dx, dy) = gaze_scroll.update(time, px);
+            if dx != 0 || dy != 0 {
+                output.scroll(dx, dy);
+            }
+        }
+
+        if config.pointing.mode == PointingMode::Absolute {
+            // Head pose alone determines the cursor position, so gaze and
+            // angular velocity are ignored.
+            if let Some(pose) = raw_head_pose.take() {
+                // On other monitors, the head points relative to their
+                // direction.
+                let pose = pose - switcher.direction();
+                let px = denormalize(
+                    &layout.screens[switcher.current()],
+                    absolute_pointer.update(pose.x, pose.y),
+                );
+
+                // Avoid flooding the display server with moves that do not
+                // change the position on screen.
+                if (px.x as i32, px.y as i32) != (anchor.x as i32, anchor.y as i32) {
+                    anchor = px;
+                    output.move_to(px.x as i32, px.y as i32);
+                }
+            }
+            continue;
+        }
+
+        let relative = &config.pointing.relative;
 
-            if angular.x.abs() > 0 || angular.y.abs() > 0 {
-                if tick.duration_since(last_head_move) < Duration::from_millis(100)
-                    && angular.x.abs() + angular.y.abs() > 5
-                {
-                    //println!("BOOST");
-                    angular.x = (angular.x * 3) / 2;
-                    angular.y = (angular.y * 3) / 2;
-                    last_head_move = tick;
-                } else if angular.x.abs() + angular.y.abs() > 2 {
-                    last_head_move = tick;
+        if tick_head {
+            let angular = accelerator.apply(vec2(
+                raw_head_angular_velocity.x * distance_to_screen * relative.horizontal_gain,
+                raw_head_angular_velocity.y * distance_to_screen,
+            ));
+
+            if angular.x != 0f32 || angular.y != 0f32 {
+                if angular.x.abs() + angular.y.abs() > relative.head_move {
+                    last_head_move = time;
                 }
-                anchor.x = anchor.x + (angular.x as f32);
-                anchor.y = anchor.y + (angular.y as f32);
-                move_cursor_relative(angular.x, angular.y);
+                // Stay on the monitors, so that the anchor does not drift
+                // away from the cursor in parts of the desktop that are not
+                // shown, or past its edges.
+                let target = layout.clamp(anchor + angular);
+                let delta = target - anchor;
+                anchor = target;
+                output.move_relative(delta.x, delta.y);
             }
         }
 
-        if tick_gaze {
-            let dt = tick.duration_since(last_head_move);
+        if tick_gaze && !switcher.is_away() {
+            // Timestamps come from different device clocks, so the head may
+            // appear to have moved after the gaze sample.
+            let dt = time.saturating_duration_since(last_head_move);
 
-            let distance_to_center = denormalize(vec2(0.5f32, 0.5f32)).distance(anchor);
+            let distance_to_center = denormalize(&screen, vec2(0.5f32, 0.5f32)).distance(anchor);
 
             // Ratio that increases as the distance between gaze point and center of screen
             // increases, but within (0;1).
             let distance_to_center_ratio =
                 fmin(1.0f32, distance_to_center / distance_to_center_max);
 
-            px_gaze = denormalize(raw_gaze);
+            px_gaze = denormalize(&screen, raw_gaze);
 
             let d = euclidean_distance(
                 px_gaze.x as i32,
@@ -223,7 +703,8 @@ fn run_pipeline(rx: Receiver<Input>) {
             );
 
             // User is still looking at anchor, so do nothing.
-            if d < 5 {
+            if (d as f32) < relative.dead_zone {
+                rejump = false;
                 continue;
             }
 
@@ -237,67 +718,133 @@ fn run_pipeline(rx: Receiver<Input>) {
             //     to be expected at the edges.
 
             // Maximum distance of a jump on the display.
-            // TODO: Avoid recomputation of this number over and over again?
-            let display_max: f32 = (display_x.powi(2) + display_y.powi(2)).sqrt();
+            let display_max: f32 = (screen.width.powi(2) + screen.height.powi(2)).sqrt();
 
             // Ratio that increases proportional to the distance between the gaze point and the
             // anchor point, but within (0;1).
             let max_ratio: f32 = fmin(1.0f32, (d as f32) / display_max);
 
-            let threshold_as_millis = ((100.0 + 1100.0
-                - fmin(600.0, (max_ratio * distance_to_center_ratio * 24000.0)))
-                as u64);
-            let threshold = Duration::from_millis(threshold_as_millis);
-            //println!("threshold: {}", threshold_as_millis );
-            if dt < threshold {
+            let threshold = relative.jump_delay
+                - fmin(
+                    relative.jump_delay_reduction,
+                    max_ratio * distance_to_center_ratio * relative.jump_delay_gain,
+                );
+            let threshold = Duration::from_secs_f32(threshold);
+            //println!("threshold: {:?}", threshold);
+            if dt < threshold && !rejump {
                 //println!("cannot move... tooo close");
                 continue;
             }
 
-            px_gaze = px_gaze + vec2(offset_x, offset_y);
+            // Targets under the zoom are not where they appear.
+            if let Some(target) = snapper.snap(px_gaze).filter(|_| !output.is_open()) {
+                px_gaze = target;
+            }
 
-            if d > 30 || (anchor.x == 0f32 && anchor.y == 0f32) {
+            if rejump
+                || d as f32 > relative.jump_distance
+                || (anchor.x == 0f32 && anchor.y == 0f32)
+            {
+                rejump = false;
                 anchor = px_gaze;
-                enigo.mouse_move_to(px_gaze.x as i32, px_gaze.y as i32);
-            } else if dt > Duration::from_millis(1000) {
-                if d > 20 {
+                output.move_to(px_gaze.x as i32, px_gaze.y as i32);
+            } else if dt > Duration::from_secs_f32(relative.settle_time) {
+                if d as f32 > relative.settle_distance {
                 anchor = px_gaze;
-                enigo.mouse_move_to(px_gaze.x as i32, px_gaze.y as i32);
+                output.move_to(px_gaze.x as i32, px_gaze.y as i32);
                 }
             else  {
                 px_gaze = vec2((px_gaze.x + anchor.x) / 2f32, (px_gaze.y + anchor.y) / 2f32);
-                enigo.mouse_move_to(px_gaze.x as i32, px_gaze.y as i32);
+                output.move_to(px_gaze.x as i32, px_gaze.y as i32);
             }
             }
         }
     }
 }
 
-fn rotate(v: Vector2<f32>, degrees: f32) -> Vector2<f32> {
-    let bias = degrees.to_radians();
-    vec2(
-        v.x * bias.cos() - v.y * bias.sin(),
-        v.x * bias.sin() + v.y * bias.cos(),
-    )
-}
-
-fn denormalize(p: Vector2<f32>) -> Vector2<f32> {
+fn denormalize(screen: &Screen, p: Vector2<f32>) -> Vector2<f32> {
     if p.x.is_nan() || p.y.is_nan() {
         println!("encountered NaN!");
-        return vec2(0f32, 0f32);
+        return screen.origin();
     }
-    vec2(p.x * display_x, p.y * display_y)
+    screen.denormalize(p)
 }
 
 fn euclidean_distance(x1: i32, y1: i32, x2: i32, y2: i32) -> i32 {
     (((x1 - x2) as f64).powi(2) + ((y1 - y2) as f64).powi(2)).sqrt() as i32
 }
 
-fn main() {
-    let (mut pool, rx) = InputPool::new();
-    pool.spawn(tobii_input::listen);
-    pool.spawn(gyro_input::listen);
+fn run(config: Config) {
+    let gyro = config.gyro.clone();
+    let tobii = config.tobii.clone();
 
-    let handle = thread::spawn(|| run_pipeline(rx));
+    let (mut pool, rx) = InputPool::new();
+    // The eye tracker input waits for and reconnects to the device by itself,
+    // so it only exits on errors or if told not to wait.
+    let tobii_restart = if tobii.wait_for_device {
+        Restart::Always
+    } else {
+        Restart::Never
+    };
+    pool.spawn("tobii", tobii_restart, move |output, inbox| {
+        tobii_input::listen(output, inbox, tobii.clone())
+    });
+    pool.spawn("gyro", Restart::Times(5), move |output, inbox| {
+        gyro_input::listen(output, inbox, gyro.clone())
+    });
+    pool.spawn("config", Restart::Times(5), reload::listen);
+
+    let status = SharedStatus::default();
+    let stats = SharedStats::new(Mutex::new(Stats::new()));
+    status::serve(status.clone(), stats.clone(), rx.sender("status"));
+
+    let handle = thread::spawn(move || run_pipeline(&pool, rx, config, status, stats));
     handle.join().unwrap();
 }
+
+fn query(command: &str) {
+    match status::request(command) {
+        Ok(response) => print!("{}", response),
+        Err(e) => {
+            println!("Could not reach glimpse: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    println!("Usage:");
+    println!("  glimpse [--device <device>]    Run, optionally with the eye tracker");
+    println!("                                 given by URL, serial number or model.");
+    println!("  glimpse devices                List eye trackers.");
+    println!("  glimpse status                 Show status of the running instance.");
+    println!("  glimpse stats [reset]          Show (or clear) latency statistics.");
+    println!("  glimpse recenter               Take the head pose as neutral pose.");
+    println!("  glimpse gyro calibrate-mount   Estimate how the IMU is mounted.");
+    println!("  glimpse screens                List monitors.");
+    process::exit(2);
+}
+
+fn main() {
+    let mut config = Config::load().unwrap_or_else(|e| {
+        println!("Could not load {}: {:?}", config::path().display(), e);
+        process::exit(1);
+    });
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => run(config),
+        ["--device", device] => {
+            config.tobii.device = Some(device.to_string());
+            run(config)
+        }
+        ["devices"] => tobii_input::devices(),
+        ["status"] => query("status"),
+        ["stats"] => query("stats"),
+        ["stats", "reset"] => query("stats reset"),
+        ["recenter"] => query("recenter"),
+        ["gyro", "calibrate-mount"] => mount_calibration::run(config),
+        ["screens"] => screens::list(&config.screens),
+        _ => usage(),
+    }
+}
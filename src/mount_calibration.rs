@@ -0,0 +1,322 @@
+// Guided estimation of how the IMU is mounted on the head.
+//
+// While the user shakes their head, angular velocity is concentrated around
+// the yaw axis of the head, and while they nod, around the pitch axis. Both
+// axes are recovered in the frame of the IMU as the principal axis of the
+// angular velocities observed during the respective phase. The sign of each
+// axis is taken from the first pronounced movement, which the user is asked
+// to make in a known direction.
+//
+// The result is written to the configuration file as `mount` in `[gyro]`,
+// leaving the rest of the file as it is.
+
+use cgmath::prelude::InnerSpace;
+use cgmath::{vec3, Matrix3, Vector3};
+
+use std::fs;
+use std::io;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bus;
+use crate::config::{self, Config};
+use crate::gyro_input;
+use crate::inputs::{Event, Input, InputPool, Restart};
+
+const PREPARE: Duration = Duration::from_secs(3);
+const STILL: Duration = Duration::from_secs(2);
+const GESTURE: Duration = Duration::from_secs(5);
+
+// Angular velocity [° / s] that counts as a deliberate movement when
+// determining the direction of an axis.
+const PRONOUNCED: f32 = 30f32;
+
+// Angular velocities measured during `duration` from `start`. Samples that
+// were queued up before, e.g. while the user was preparing, are skipped.
+fn collect(rx: &bus::Receiver, start: Instant, duration: Duration) -> Vec<Vector3<f32>> {
+    let mut samples = vec![];
+    while start.elapsed() < duration {
+        match rx.recv_timeout(duration - start.elapsed().min(duration)) {
+            Ok(Event {
+                time,
+                input: Input::Gyro { x, y, z },
+                ..
+            }) if time >= start => samples.push(vec3(x, y, z)),
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    samples
+}
+
+// Returns when the user was told to start.
+fn prompt(message: &str) -> Instant {
+    println!("{}", message);
+    thread::sleep(PREPARE);
+    println!("Go!");
+    Instant::now()
+}
+
+// Principal axis of the samples, i.e. the eigenvector of their scatter
+// matrix with the largest eigenvalue, found by power iteration. The sign is
+// chosen such that the first pronounced sample points along the axis.
+fn principal_axis(samples: &[Vector3<f32>]) -> Option<Vector3<f32>> {
+    let zero = vec3(0f32, 0f32, 0f32);
+    let mut scatter = Matrix3::from_cols(zero, zero, zero);
+    for w in samples {
+        scatter = scatter + Matrix3::from_cols(*w * w.x, *w * w.y, *w * w.z);
+    }
+
+    let mut axis = vec3(1f32, 1f32, 1f32).normalize();
+    for _ in 0..64 {
+        let next = scatter * axis;
+        if next.magnitude() == 0f32 {
+            return None;
+        }
+        axis = next.normalize();
+    }
+
+    let first = samples.iter().find(|w| w.dot(axis).abs() > PRONOUNCED)?;
+    if first.dot(axis) < 0f32 {
+        axis = -axis;
+    }
+    Some(axis)
+}
+
+fn estimate(rx: &bus::Receiver) -> Option<[[f32; 3]; 3]> {
+    let start = prompt("Keep your head still.");
+    let still = collect(rx, start, STILL);
+    if still.is_empty() {
+        println!("Did not receive any data from the IMU.");
+        return None;
+    }
+    let bias = still.iter().fold(vec3(0f32, 0f32, 0f32), |a, b| a + *b) / still.len() as f32;
+
+    let start = prompt("Shake your head (\"no\"), starting by turning to the right.");
+    let shake: Vec<_> = collect(rx, start, GESTURE)
+        .into_iter()
+        .map(|w| w - bias)
+        .collect();
+
+    let start = prompt("Nod your head (\"yes\"), starting by looking down.");
+    let nod: Vec<_> = collect(rx, start, GESTURE)
+        .into_iter()
+        .map(|w| w - bias)
+        .collect();
+
+    let yaw = principal_axis(&shake);
+    let pitch = principal_axis(&nod);
+    let (yaw, pitch) = match (yaw, pitch) {
+        (Some(yaw), Some(pitch)) => (yaw, pitch),
+        _ => {
+            println!("Movement was not pronounced enough.");
+            return None;
+        }
+    };
+
+    // Make the axes orthonormal, trusting the yaw axis more since shaking
+    // tends to be the cleaner gesture.
+    let pitch = pitch - yaw * pitch.dot(yaw);
+    if pitch.magnitude() < 0.5f32 {
+        println!("Shaking and nodding were too similar.");
+        return None;
+    }
+    let pitch = pitch.normalize();
+    let roll = yaw.cross(pitch);
+
+    Some([yaw.into(), pitch.into(), roll.into()])
+}
+
+// Net change in nesting of brackets on a line of TOML.
+fn nesting(code: &str) -> i32 {
+    code.chars()
+        .map(|c| match c {
+            '[' => 1,
+            ']' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Sets `mount` in the `[gyro]` table of the configuration `contents`, and
+// leaves all other lines as they are. Returns `None` if the result would not
+// hold the mount, e.g. because the table is written in an unusual way.
+fn set_mount(contents: &str, mount: [[f32; 3]; 3]) -> Option<String> {
+    let entry = format!("mount = {}", toml::Value::try_from(mount).ok()?);
+    let mut lines: Vec<String> = contents.lines().map(String::from).collect();
+
+    match lines.iter().position(|line| line.trim() == "[gyro]") {
+        Some(header) => {
+            // Find the existing entry, which may span several lines.
+            let mut depth = 0;
+            let mut start = None;
+            let mut end = None;
+            for (i, line) in lines.iter().enumerate().skip(header + 1) {
+                let code = line.split('#').next().unwrap_or("").trim();
+                if depth == 0 && code.starts_with('[') {
+                    break;
+                }
+                if depth == 0 && code.split('=').next().map(str::trim) == Some("mount") {
+                    start = Some(i);
+                }
+                depth += nesting(code);
+                if depth == 0 && start.is_some() {
+                    end = Some(i);
+                    break;
+                }
+            }
+            match (start, end) {
+                (Some(start), Some(end)) => {
+                    lines.splice(start..=end, vec![entry]);
+                }
+                _ => lines.insert(header + 1, entry),
+            }
+        }
+        None => {
+            if lines.last().map_or(false, |line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push("[gyro]".to_string());
+            lines.push(entry);
+        }
+    }
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    let config: Config = toml::from_str(&updated).ok()?;
+    if config.gyro.mount != mount {
+        return None;
+    }
+    Some(updated)
+}
+
+fn save(mount: [[f32; 3]; 3]) -> io::Result<()> {
+    let path = config::path();
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let updated = set_mount(&contents, mount).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "could not find where to put the mount",
+        )
+    })?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, updated)
+}
+
+pub fn run(config: Config) {
+    // Receive angular velocity in the frame of the IMU.
+    let mut gyro = config.gyro.clone();
+    gyro.mount = [[1f32, 0f32, 0f32], [0f32, 1f32, 0f32], [0f32, 0f32, 1f32]];
+
+    let (mut pool, rx) = InputPool::new();
+    pool.spawn("gyro", Restart::Never, move |output, inbox| {
+        gyro_input::listen(output, inbox, gyro.clone())
+    });
+
+    match estimate(&rx) {
+        Some(mount) => {
+            println!("Estimated mount: {:?}", mount);
+            let path = config::path();
+            if let Err(e) = save(mount) {
+                println!("Could not save to {}: {}", path.display(), e);
+                if let Ok(mount) = toml::Value::try_from(mount) {
+                    println!("Add this to the [gyro] section instead:");
+                    println!("mount = {}", mount);
+                }
+                process::exit(1);
+            }
+            println!("Saved to {}.", path.display());
+        }
+        None => process::exit(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNT: [[f32; 3]; 3] = [[0f32, 1f32, 0f32], [-1f32, 0f32, 0f32], [0f32, 0f32, 1f32]];
+
+    #[test]
+    fn skips_stale_samples() {
+        let rx = bus::new();
+        let tx = rx.sender("gyro");
+        let start = Instant::now();
+        let before = start - Duration::from_secs(1);
+        for _ in 0..10 {
+            let stale = Input::Gyro {
+                x: 100f32,
+                y: 0f32,
+                z: 0f32,
+            };
+            tx.send(Event::new(before, stale)).unwrap();
+        }
+        let fresh = Input::Gyro {
+            x: 0f32,
+            y: 1f32,
+            z: 0f32,
+        };
+        tx.send(Event::new(start, fresh)).unwrap();
+
+        let samples = collect(&rx, start, Duration::from_millis(50));
+        assert_eq!(samples, vec![vec3(0f32, 1f32, 0f32)]);
+    }
+
+    fn mount(contents: &str) -> [[f32; 3]; 3] {
+        toml::from_str::<Config>(contents).unwrap().gyro.mount
+    }
+
+    #[test]
+    fn replaces_mount_only() {
+        let contents = "\
+# Tuned by hand.
+[pointing]
+mode = \"absolute\"
+
+[gyro]
+tty = \"/dev/ttyACM0\" # the dongle
+mount = [
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+]
+baud_rate = 115200
+
+[dwell]
+enabled = true
+";
+        let updated = set_mount(contents, MOUNT).unwrap();
+        assert_eq!(mount(&updated), MOUNT);
+        let expected = contents.replace(
+            "mount = [\n    [1.0, 0.0, 0.0],\n    [0.0, 1.0, 0.0],\n    [0.0, 0.0, 1.0],\n]",
+            "mount = [[0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]",
+        );
+        assert_eq!(updated, expected);
+    }
+
+    #[test]
+    fn adds_mount() {
+        let updated = set_mount("[gyro]\nbaud_rate = 115200\n", MOUNT).unwrap();
+        assert_eq!(mount(&updated), MOUNT);
+        assert!(updated.contains("baud_rate = 115200"));
+
+        let updated = set_mount("[dwell]\nenabled = true\n", MOUNT).unwrap();
+        assert_eq!(mount(&updated), MOUNT);
+        assert!(updated.starts_with("[dwell]\nenabled = true\n\n[gyro]\n"));
+
+        assert_eq!(mount(&set_mount("", MOUNT).unwrap()), MOUNT);
+    }
+
+    #[test]
+    fn refuses_unusual_layouts() {
+        // The mount is set with a dotted key elsewhere.
+        assert_eq!(set_mount("gyro.tty = \"/dev/ttyACM0\"\n", MOUNT), None);
+    }
+}
@@ -0,0 +1,527 @@
+// Backends that move the cursor.
+//
+//  * `xdotool`: Warps through enigo and spawns xdotool for relative moves.
+//  * `xtest`: Talks to the X server directly. If XInput 2 is available,
+//    relative moves are performed by warping to a position with 16.16 fixed
+//    point precision, so fractional movement is not lost. Otherwise XTest
+//    relative motion events are faked.
+//  * `uinput`: Creates a virtual mouse in the kernel, so that movement goes
+//    through the regular input stack (including libinput acceleration, if
+//    configured). Warps still go through X.
+//
+// All backends can also press and release buttons, see `dwell`, and scroll.
+//
+// Backends that can only move by whole pixels carry the fractional remainder
+// of every relative move over to the next one.
+
+use cgmath::{vec2, Vector2};
+use enigo::{Enigo, MouseButton, MouseControllable};
+use serde::{Deserialize, Serialize};
+use uinput::event::controller::Controller::Mouse;
+use uinput::event::controller::Mouse::{Left, Middle, Right};
+use uinput::event::relative::Position::{X, Y};
+use uinput::event::relative::Relative::{Position, Wheel};
+use uinput::event::relative::Wheel::{Horizontal, Vertical};
+use uinput::event::Event::{Controller, Relative};
+use x11rb::connection::Connection;
+use x11rb::protocol::xinput::{ConnectionExt as _, DeviceId};
+use x11rb::protocol::xproto::{self, ConnectionExt as _, Window};
+use x11rb::protocol::xtest::ConnectionExt as _;
+use x11rb::rust_connection::RustConnection;
+
+use std::error::Error;
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Xdotool,
+    Xtest,
+    Uinput,
+}
+
+pub trait Output {
+    // Warp to an absolute position [px].
+    fn move_to(&mut self, x: i32, y: i32);
+
+    // Move by a relative amount [px], which may be fractional.
+    fn move_relative(&mut self, dx: f32, dy: f32);
+
+    // Current position [px] of the cursor, if it can be determined.
+    fn location(&mut self) -> Option<(i32, i32)>;
+
+    fn press(&mut self, button: Button);
+
+    fn release(&mut self, button: Button);
+
+    fn click(&mut self, button: Button) {
+        self.press(button);
+        self.release(button);
+    }
+
+    // Scroll by steps of the wheel, down and right are positive.
+    fn scroll(&mut self, dx: i32, dy: i32);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Left,
+    Middle,
+    Right,
+}
+
+impl Button {
+    // Number of the button in X.
+    fn number(self) -> u8 {
+        match self {
+            Button::Left => 1,
+            Button::Middle => 2,
+            Button::Right => 3,
+        }
+    }
+}
+
+pub fn create(backend: Backend) -> Box<dyn Output> {
+    match backend {
+        Backend::Xdotool => Box::new(Xdotool::new()),
+        Backend::Xtest => match XTest::new() {
+            Ok(output) => Box::new(output),
+            Err(e) => {
+                println!("Could not initialize XTest, using xdotool: {}", e);
+                Box::new(Xdotool::new())
+            }
+        },
+        Backend::Uinput => match Uinput::new() {
+            Ok(output) => Box::new(output),
+            Err(e) => {
+                println!("Could not initialize uinput, using xdotool: {:?}", e);
+                Box::new(Xdotool::new())
+            }
+        },
+    }
+}
+
+// Splits movement into whole pixels and remembers the rest.
+pub struct Subpixel {
+    remainder: Vector2<f32>,
+}
+
+impl Subpixel {
+    pub fn new() -> Subpixel {
+        Subpixel {
+            remainder: vec2(0f32, 0f32),
+        }
+    }
+
+    pub fn split(&mut self, dx: f32, dy: f32) -> (i32, i32) {
+        let total = vec2(dx, dy) + self.remainder;
+        let whole = vec2(total.x.trunc(), total.y.trunc());
+        self.remainder = total - whole;
+        (whole.x as i32, whole.y as i32)
+    }
+}
+
+pub struct Xdotool {
+    enigo: Enigo,
+    subpixel: Subpixel,
+}
+
+impl Xdotool {
+    pub fn new() -> Xdotool {
+        Xdotool {
+            enigo: Enigo::new(),
+            subpixel: Subpixel::new(),
+        }
+    }
+}
+
+impl Output for Xdotool {
+    fn move_to(&mut self, x: i32, y: i32) {
+        self.enigo.mouse_move_to(x, y);
+    }
+
+    fn move_relative(&mut self, dx: f32, dy: f32) {
+        let (x, y) = self.subpixel.split(dx, dy);
+        if x == 0 && y == 0 {
+            return;
+        }
+        // TODO(lorenz.leutgeb): Look for a native solution to move the cursor.
+        // Spawning xdotool adds a dependency and is quite heavy.
+        let status = Command::new("xdotool")
+            .arg("mousemove_relative")
+            .arg("--")
+            .arg((x).to_string())
+            .arg((y).to_string())
+            .status();
+        if let Err(e) = status {
+            println!("Could not run xdotool: {}", e);
+        }
+    }
+
+    fn location(&mut self) -> Option<(i32, i32)> {
+        // TODO(lorenz.leutgeb): Look for a faster version to obtain the current
+        // cursor position. Spawning xdotool adds a dependency and is quite heavy.
+        let output = match Command::new("xdotool")
+            .arg("getmouselocation")
+            .arg("--shell")
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                println!("Could not run xdotool: {}", e);
+                return None;
+            }
+        };
+
+        // One variable per line, e.g. "X=640".
+        let text = String::from_utf8_lossy(&output.stdout);
+        let value = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.parse::<i32>().ok())
+        };
+        match (value("X="), value("Y=")) {
+            (Some(x), Some(y)) => Some((x, y)),
+            _ => {
+                println!("Could not read cursor location from xdotool: {:?}", text);
+                None
+            }
+        }
+    }
+
+    fn press(&mut self, button: Button) {
+        self.enigo.mouse_down(enigo_button(button));
+    }
+
+    fn release(&mut self, button: Button) {
+        self.enigo.mouse_up(enigo_button(button));
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        if dx != 0 {
+            self.enigo.mouse_scroll_x(dx);
+        }
+        if dy != 0 {
+            self.enigo.mouse_scroll_y(dy);
+        }
+    }
+}
+
+fn enigo_button(button: Button) -> MouseButton {
+    match button {
+        Button::Left => MouseButton::Left,
+        Button::Middle => MouseButton::Middle,
+        Button::Right => MouseButton::Right,
+    }
+}
+
+fn to_fp1616(v: f32) -> i32 {
+    (v * 65536f32) as i32
+}
+
+fn from_fp1616(v: i32) -> f32 {
+    v as f32 / 65536f32
+}
+
+pub struct XTest {
+    conn: RustConnection,
+    root: Window,
+    // Master pointer, if XInput 2 is available.
+    pointer: Option<DeviceId>,
+    subpixel: Subpixel,
+}
+
+impl XTest {
+    pub fn new() -> Result<XTest, Box<dyn Error>> {
+        let (conn, screen) = RustConnection::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+
+        let xi2 = match conn.xinput_xi_query_version(2, 0) {
+            Ok(cookie) => cookie
+                .reply()
+                .map(|r| r.major_version >= 2)
+                .unwrap_or(false),
+            Err(_) => false,
+        };
+        let pointer = if xi2 {
+            Some(
+                conn.xinput_xi_get_client_pointer(x11rb::NONE)?
+                    .reply()?
+                    .deviceid,
+            )
+        } else {
+            None
+        };
+
+        Ok(XTest {
+            conn,
+            root,
+            pointer,
+            subpixel: Subpixel::new(),
+        })
+    }
+
+    fn try_move_relative(&mut self, dx: f32, dy: f32) -> Result<(), Box<dyn Error>> {
+        match self.pointer {
+            Some(device) => {
+                let current = self
+                    .conn
+                    .xinput_xi_query_pointer(self.root, device)?
+                    .reply()?;
+                self.conn.xinput_xi_warp_pointer(
+                    x11rb::NONE,
+                    self.root,
+                    0,
+                    0,
+                    0,
+                    0,
+                    to_fp1616(from_fp1616(current.root_x) + dx),
+                    to_fp1616(from_fp1616(current.root_y) + dy),
+                    device,
+                )?;
+            }
+            None => {
+                let (x, y) = self.subpixel.split(dx, dy);
+                if x == 0 && y == 0 {
+                    return Ok(());
+                }
+                // A detail of 1 makes the motion relative.
+                self.conn.xtest_fake_input(
+                    xproto::MOTION_NOTIFY_EVENT,
+                    1,
+                    x11rb::CURRENT_TIME,
+                    x11rb::NONE,
+                    x as i16,
+                    y as i16,
+                    0,
+                )?;
+            }
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn try_location(&self) -> Result<(i32, i32), Box<dyn Error>> {
+        let reply = self.conn.query_pointer(self.root)?.reply()?;
+        Ok((reply.root_x as i32, reply.root_y as i32))
+    }
+
+    fn fake_button(&self, event: u8, button: u8) -> Result<(), Box<dyn Error>> {
+        self.conn
+            .xtest_fake_input(event, button, x11rb::CURRENT_TIME, x11rb::NONE, 0, 0, 0)?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    // X scrolls by clicking buttons 4 to 7 (up, down, left, right).
+    fn try_scroll(&self, dx: i32, dy: i32) -> Result<(), Box<dyn Error>> {
+        let steps = [(dy, 4u8, 5u8), (dx, 6u8, 7u8)];
+        for &(count, negative, positive) in steps.iter() {
+            let button = if count < 0 { negative } else { positive };
+            for _ in 0..count.abs() {
+                self.fake_button(xproto::BUTTON_PRESS_EVENT, button)?;
+                self.fake_button(xproto::BUTTON_RELEASE_EVENT, button)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Output for XTest {
+    fn move_to(&mut self, x: i32, y: i32) {
+        let result = self
+            .conn
+            .warp_pointer(x11rb::NONE, self.root, 0, 0, 0, 0, x as i16, y as i16)
+            .map(|_| ())
+            .and_then(|_| self.conn.flush());
+        if let Err(e) = result {
+            println!("Could not warp: {}", e);
+        }
+    }
+
+    fn move_relative(&mut self, dx: f32, dy: f32) {
+        if let Err(e) = self.try_move_relative(dx, dy) {
+            println!("Could not move: {}", e);
+        }
+    }
+
+    fn location(&mut self) -> Option<(i32, i32)> {
+        match self.try_location() {
+            Ok(location) => Some(location),
+            Err(e) => {
+                println!("Could not query pointer: {}", e);
+                None
+            }
+        }
+    }
+
+    fn press(&mut self, button: Button) {
+        if let Err(e) = self.fake_button(xproto::BUTTON_PRESS_EVENT, button.number()) {
+            println!("Could not press {:?}: {}", button, e);
+        }
+    }
+
+    fn release(&mut self, button: Button) {
+        if let Err(e) = self.fake_button(xproto::BUTTON_RELEASE_EVENT, button.number()) {
+            println!("Could not release {:?}: {}", button, e);
+        }
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        if let Err(e) = self.try_scroll(dx, dy) {
+            println!("Could not scroll: {}", e);
+        }
+    }
+}
+
+pub struct Uinput {
+    device: uinput::Device,
+    subpixel: Subpixel,
+    // uinput cannot warp a relative device, so warps go through X.
+    warp: Xdotool,
+}
+
+impl Uinput {
+    pub fn new() -> Result<Uinput, uinput::Error> {
+        let device = uinput::default()?
+            .name("glimpse")?
+            // Without a button, the device is not recognized as a mouse.
+            .event(Controller(Mouse(Left)))?
+            .event(Controller(Mouse(Middle)))?
+            .event(Controller(Mouse(Right)))?
+            .event(Relative(Position(X)))?
+            .event(Relative(Position(Y)))?
+            .event(Relative(Wheel(Vertical)))?
+            .event(Relative(Wheel(Horizontal)))?
+            .create()?;
+
+        Ok(Uinput {
+            device,
+            subpixel: Subpixel::new(),
+            warp: Xdotool::new(),
+        })
+    }
+
+    fn try_move_relative(&mut self, x: i32, y: i32) -> Result<(), uinput::Error> {
+        self.device.send(X, x)?;
+        self.device.send(Y, y)?;
+        self.device.synchronize()
+    }
+
+    fn try_button(&mut self, button: Button, down: bool) -> Result<(), uinput::Error> {
+        let button = match button {
+            Button::Left => Mouse(Left),
+            Button::Middle => Mouse(Middle),
+            Button::Right => Mouse(Right),
+        };
+        if down {
+            self.device.press(&button)?;
+        } else {
+            self.device.release(&button)?;
+        }
+        self.device.synchronize()
+    }
+
+    fn try_scroll(&mut self, dx: i32, dy: i32) -> Result<(), uinput::Error> {
+        self.device.send(Horizontal, dx)?;
+        // The wheel scrolls up for positive values.
+        self.device.send(Vertical, -dy)?;
+        self.device.synchronize()
+    }
+}
+
+impl Output for Uinput {
+    fn move_to(&mut self, x: i32, y: i32) {
+        self.warp.move_to(x, y);
+    }
+
+    fn move_relative(&mut self, dx: f32, dy: f32) {
+        let (x, y) = self.subpixel.split(dx, dy);
+        if x == 0 && y == 0 {
+            return;
+        }
+        if let Err(e) = self.try_move_relative(x, y) {
+            println!("Could not move: {:?}", e);
+        }
+    }
+
+    fn location(&mut self) -> Option<(i32, i32)> {
+        self.warp.location()
+    }
+
+    fn press(&mut self, button: Button) {
+        if let Err(e) = self.try_button(button, true) {
+            println!("Could not press {:?}: {:?}", button, e);
+        }
+    }
+
+    fn release(&mut self, button: Button) {
+        if let Err(e) = self.try_button(button, false) {
+            println!("Could not release {:?}: {:?}", button, e);
+        }
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        if let Err(e) = self.try_scroll(dx, dy) {
+            println!("Could not scroll: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds `deltas` and returns the whole pixels emitted in total. Deltas
+    // are exact in binary, so the totals are exact too.
+    fn emitted(subpixel: &mut Subpixel, deltas: &[(f32, f32)]) -> (i32, i32) {
+        deltas.iter().fold((0, 0), |(x, y), &(dx, dy)| {
+            let (sx, sy) = subpixel.split(dx, dy);
+            assert!(subpixel.remainder.x.abs() < 1f32 && subpixel.remainder.y.abs() < 1f32);
+            (x + sx, y + sy)
+        })
+    }
+
+    #[test]
+    fn small_positive_moves_add_up() {
+        let mut subpixel = Subpixel::new();
+        let deltas = vec![(0.25f32, 0.125f32); 400];
+        assert_eq!(emitted(&mut subpixel, &deltas), (100, 50));
+    }
+
+    #[test]
+    fn small_negative_moves_add_up() {
+        let mut subpixel = Subpixel::new();
+        let deltas = vec![(-0.25f32, -0.375f32); 400];
+        assert_eq!(emitted(&mut subpixel, &deltas), (-100, -150));
+    }
+
+    #[test]
+    fn moves_changing_direction_add_up() {
+        let mut subpixel = Subpixel::new();
+        let deltas: Vec<(f32, f32)> = (0..400)
+            .map(|i| {
+                if i % 2 == 0 {
+                    (0.75f32, -0.5f32)
+                } else {
+                    (-0.5f32, 0.25f32)
+                }
+            })
+            .collect();
+        assert_eq!(emitted(&mut subpixel, &deltas), (50, -50));
+    }
+
+    #[test]
+    fn jitter_does_not_drift() {
+        let mut subpixel = Subpixel::new();
+        let deltas: Vec<(f32, f32)> = (0..400)
+            .map(|i| {
+                if i % 2 == 0 {
+                    (0.5f32, -0.5f32)
+                } else {
+                    (-0.5f32, 0.5f32)
+                }
+            })
+            .collect();
+        assert_eq!(emitted(&mut subpixel, &deltas), (0, 0));
+    }
+}
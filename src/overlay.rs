@@ -0,0 +1,289 @@
+// Small windows drawn on top of everything else, for feedback on dwell
+// clicking:
+//
+//  * The indicator follows the cursor and fills up while the cursor dwells.
+//    It is placed next to the cursor rather than under it, so that clicks
+//    reach the window the user is pointing at.
+//  * The palette sits at the right edge of the screen and offers the click
+//    types. Dwelling on one of its buttons selects that click type.
+//
+// Both are override-redirect windows, so the window manager neither
+// decorates nor moves them.
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    Arc, ConfigureWindowAux, ConnectionExt as _, CreateGCAux, CreateWindowAux, EventMask,
+    Gcontext, Rectangle, StackMode, Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use std::error::Error;
+
+use crate::dwell::ClickType;
+
+// Diameter [px] of the indicator.
+const INDICATOR_SIZE: u16 = 24;
+// Offset [px] of the indicator from the cursor.
+const INDICATOR_OFFSET: i16 = 16;
+// Width and height [px] of a palette button.
+const BUTTON_SIZE: u16 = 48;
+
+const PALETTE: [(ClickType, &str); 4] = [
+    (ClickType::Left, "L"),
+    (ClickType::Right, "R"),
+    (ClickType::Double, "2x"),
+    (ClickType::Drag, "Drag"),
+];
+
+pub struct Overlay {
+    conn: RustConnection,
+    indicator: Window,
+    indicator_shown: bool,
+    progress: f32,
+    palette: Option<Window>,
+    // Top left corner [px] of the palette.
+    palette_origin: (i16, i16),
+    selected: ClickType,
+    // Black on white, and white on black.
+    dark: Gcontext,
+    light: Gcontext,
+}
+
+impl Overlay {
+    pub fn new(palette: bool) -> Result<Overlay, Box<dyn Error>> {
+        let (conn, screen) = RustConnection::connect(None)?;
+        let screen = conn.setup().roots[screen].clone();
+
+        let create = |x: i16, y: i16, width: u16, height: u16| -> Result<Window, Box<dyn Error>> {
+            let window = conn.generate_id()?;
+            conn.create_window(
+                x11rb::COPY_DEPTH_FROM_PARENT,
+                window,
+                screen.root,
+                x,
+                y,
+                width,
+                height,
+                1,
+                WindowClass::INPUT_OUTPUT,
+                x11rb::COPY_FROM_PARENT,
+                &CreateWindowAux::new()
+                    .background_pixel(screen.white_pixel)
+                    .border_pixel(screen.black_pixel)
+                    .override_redirect(1)
+                    .event_mask(u32::from(EventMask::EXPOSURE)),
+            )?;
+            Ok(window)
+        };
+
+        let indicator = create(0, 0, INDICATOR_SIZE, INDICATOR_SIZE)?;
+
+        let palette_origin = (
+            (screen.width_in_pixels - BUTTON_SIZE - 2) as i16,
+            ((screen.height_in_pixels - PALETTE.len() as u16 * BUTTON_SIZE) / 2) as i16,
+        );
+        let palette = if palette {
+            let window = create(
+                palette_origin.0,
+                palette_origin.1,
+                BUTTON_SIZE,
+                PALETTE.len() as u16 * BUTTON_SIZE,
+            )?;
+            conn.map_window(window)?;
+            Some(window)
+        } else {
+            None
+        };
+
+        let font = conn.generate_id()?;
+        conn.open_font(font, b"fixed")?;
+        let dark = conn.generate_id()?;
+        conn.create_gc(
+            dark,
+            screen.root,
+            &CreateGCAux::new()
+                .foreground(screen.black_pixel)
+                .background(screen.white_pixel)
+                .font(font),
+        )?;
+        let light = conn.generate_id()?;
+        conn.create_gc(
+            light,
+            screen.root,
+            &CreateGCAux::new()
+                .foreground(screen.white_pixel)
+                .background(screen.black_pixel)
+                .font(font),
+        )?;
+        conn.flush()?;
+
+        let mut overlay = Overlay {
+            conn,
+            indicator,
+            indicator_shown: false,
+            progress: 0f32,
+            palette,
+            palette_origin,
+            selected: ClickType::Left,
+            dark,
+            light,
+        };
+        overlay.draw_palette()?;
+        Ok(overlay)
+    }
+
+    // Shows the indicator next to the cursor at `x`, `y`, filled by
+    // `progress` within (0;1).
+    pub fn show(&mut self, x: i32, y: i32, progress: f32) {
+        if let Err(e) = self.try_show(x, y, progress) {
+            println!("Could not draw dwell indicator: {}", e);
+        }
+    }
+
+    pub fn hide(&mut self) {
+        if !self.indicator_shown {
+            return;
+        }
+        self.indicator_shown = false;
+        let result = self
+            .conn
+            .unmap_window(self.indicator)
+            .map(|_| ())
+            .and_then(|_| self.conn.flush());
+        if let Err(e) = result {
+            println!("Could not hide dwell indicator: {}", e);
+        }
+    }
+
+    // Click type offered by the palette button at `x`, `y`, if any.
+    pub fn palette_hit(&self, x: i32, y: i32) -> Option<ClickType> {
+        self.palette?;
+        let (left, top) = (self.palette_origin.0 as i32, self.palette_origin.1 as i32);
+        let size = BUTTON_SIZE as i32;
+        if x < left || x >= left + size || y < top {
+            return None;
+        }
+        PALETTE
+            .get(((y - top) / size) as usize)
+            .map(|(click, _)| *click)
+    }
+
+    pub fn select(&mut self, click: ClickType) {
+        self.selected = click;
+        if let Err(e) = self.draw_palette() {
+            println!("Could not draw dwell palette: {}", e);
+        }
+    }
+
+    // Redraws windows that were uncovered. Must be called regularly.
+    pub fn poll(&mut self) {
+        let mut palette = false;
+        let mut indicator = false;
+        while let Ok(Some(event)) = self.conn.poll_for_event() {
+            if let Event::Expose(expose) = event {
+                palette |= Some(expose.window) == self.palette;
+                indicator |= expose.window == self.indicator;
+            }
+        }
+        let mut result = Ok(());
+        if palette {
+            result = result.and(self.draw_palette());
+        }
+        if indicator && self.indicator_shown {
+            result = result.and(self.draw_indicator());
+        }
+        if let Err(e) = result {
+            println!("Could not redraw overlay: {}", e);
+        }
+    }
+
+    fn try_show(&mut self, x: i32, y: i32, progress: f32) -> Result<(), Box<dyn Error>> {
+        self.conn.configure_window(
+            self.indicator,
+            &ConfigureWindowAux::new()
+                .x(x + INDICATOR_OFFSET as i32)
+                .y(y + INDICATOR_OFFSET as i32)
+                .stack_mode(StackMode::ABOVE),
+        )?;
+        if !self.indicator_shown {
+            self.indicator_shown = true;
+            self.conn.map_window(self.indicator)?;
+        }
+        self.progress = progress;
+        self.draw_indicator()
+    }
+
+    fn draw_indicator(&self) -> Result<(), Box<dyn Error>> {
+        let size = INDICATOR_SIZE - 1;
+        self.conn
+            .clear_area(false, self.indicator, 0, 0, INDICATOR_SIZE, INDICATOR_SIZE)?;
+        // Angles are in 1/64 °, counterclockwise from three o'clock. Fill
+        // clockwise from twelve o'clock.
+        self.conn.poly_fill_arc(
+            self.indicator,
+            self.dark,
+            &[Arc {
+                x: 0,
+                y: 0,
+                width: size,
+                height: size,
+                angle1: 90 * 64,
+                angle2: -(self.progress.max(0f32).min(1f32) * 360f32 * 64f32) as i16,
+            }],
+        )?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn draw_palette(&self) -> Result<(), Box<dyn Error>> {
+        let window = match self.palette {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        for (i, (click, label)) in PALETTE.iter().enumerate() {
+            let (fill, text) = if *click == self.selected {
+                (self.dark, self.light)
+            } else {
+                (self.light, self.dark)
+            };
+            let top = (i as u16 * BUTTON_SIZE) as i16;
+            self.conn.poly_fill_rectangle(
+                window,
+                fill,
+                &[Rectangle {
+                    x: 0,
+                    y: top,
+                    width: BUTTON_SIZE,
+                    height: BUTTON_SIZE,
+                }],
+            )?;
+            self.conn.poly_rectangle(
+                window,
+                self.dark,
+                &[Rectangle {
+                    x: 0,
+                    y: top,
+                    width: BUTTON_SIZE - 1,
+                    height: BUTTON_SIZE - 1,
+                }],
+            )?;
+            // The fixed font is 6x13 px.
+            let x = (BUTTON_SIZE as i16 - 6 * label.len() as i16) / 2;
+            self.conn
+                .image_text8(window, text, x, top + BUTTON_SIZE as i16 / 2 + 4, label.as_bytes())?;
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        let _ = self.conn.destroy_window(self.indicator);
+        if let Some(palette) = self.palette {
+            let _ = self.conn.destroy_window(palette);
+        }
+        let _ = self.conn.flush();
+    }
+}
@@ -0,0 +1,196 @@
+// Suspends control while nobody is in front of the eye tracker.
+//
+// When the user leaves, the cursor position is remembered and the cursor is
+// no longer moved. After a configurable time of absence, the screen can be
+// locked or turned off. When the user returns, the cursor is put back where
+// it was.
+
+use cgmath::{vec2, Vector2};
+use serde::{Deserialize, Serialize};
+
+use std::process::Command;
+use std::time::Instant;
+
+use crate::config;
+use crate::output::Output;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AbsenceAction {
+    None,
+    // Lock the session via logind.
+    Lock,
+    // Turn off the display via DPMS.
+    Dpms,
+}
+
+impl AbsenceAction {
+    fn run(self) {
+        let status = match self {
+            AbsenceAction::None => return,
+            AbsenceAction::Lock => Command::new("loginctl").arg("lock-session").status(),
+            AbsenceAction::Dpms => Command::new("xset")
+                .arg("dpms")
+                .arg("force")
+                .arg("off")
+                .status(),
+        };
+        if let Err(e) = status {
+            println!("Could not run {:?}: {}", self, e);
+        }
+    }
+}
+
+pub struct Presence {
+    config: config::Presence,
+    away_since: Option<Instant>,
+    acted: bool,
+    saved: Option<(i32, i32)>,
+}
+
+impl Presence {
+    pub fn new(config: config::Presence) -> Presence {
+        Presence {
+            config,
+            away_since: None,
+            acted: false,
+            saved: None,
+        }
+    }
+
+    // Whether the cursor should be moved.
+    pub fn is_present(&self) -> bool {
+        !self.config.enabled || self.away_since.is_none()
+    }
+
+    // Handles a change of presence. Returns the position of the cursor if it
+    // was restored.
+    pub fn update(&mut self, present: bool, output: &mut dyn Output) -> Option<Vector2<f32>> {
+        if !self.config.enabled || present == self.away_since.is_none() {
+            return None;
+        }
+
+        if !present {
+            println!("User left.");
+            self.away_since = Some(Instant::now());
+            self.acted = false;
+            if self.config.restore_cursor {
+                // Without the position, there is nothing to restore.
+                self.saved = output.location();
+            }
+            return None;
+        }
+
+        println!("User returned.");
+        self.away_since = None;
+        let (x, y) = self.saved.take()?;
+        output.move_to(x, y);
+        Some(vec2(x as f32, y as f32))
+    }
+
+    // Runs the absence action once the user has been away for long enough.
+    // Must be called regularly, also when there is no input.
+    pub fn poll(&mut self, now: Instant) {
+        let away_since = match self.away_since {
+            Some(away_since) => away_since,
+            None => return,
+        };
+        let away = now.saturating_duration_since(away_since).as_secs_f32();
+        if self.acted || away < self.config.absence_timeout {
+            return;
+        }
+        self.acted = true;
+        self.config.absence_action.run();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::Button;
+    use std::time::Duration;
+
+    // Cursor that only moves.
+    struct Cursor(i32, i32);
+
+    impl Output for Cursor {
+        fn move_to(&mut self, x: i32, y: i32) {
+            *self = Cursor(x, y);
+        }
+
+        fn move_relative(&mut self, _: f32, _: f32) {}
+
+        fn location(&mut self) -> Option<(i32, i32)> {
+            Some((self.0, self.1))
+        }
+
+        fn press(&mut self, _: Button) {}
+
+        fn release(&mut self, _: Button) {}
+
+        fn scroll(&mut self, _: i32, _: i32) {}
+    }
+
+    fn presence(absence_timeout: f32) -> Presence {
+        Presence::new(config::Presence {
+            enabled: true,
+            absence_timeout,
+            ..config::Presence::default()
+        })
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn acts_after_the_absence_timeout() {
+        let mut presence = presence(1.5f32);
+        let mut cursor = Cursor(0, 0);
+        presence.poll(Instant::now() + ms(2000));
+        assert!(!presence.acted);
+
+        let left = Instant::now();
+        presence.update(false, &mut cursor);
+        assert!(!presence.is_present());
+        presence.poll(left + ms(1400));
+        assert!(!presence.acted);
+        presence.poll(left + ms(1600));
+        assert!(presence.acted);
+
+        // Back and away again, the timeout starts over.
+        presence.update(true, &mut cursor);
+        assert!(presence.is_present());
+        let left = Instant::now();
+        presence.update(false, &mut cursor);
+        assert!(!presence.acted);
+        presence.poll(left + ms(1400));
+        assert!(!presence.acted);
+    }
+
+    #[test]
+    fn restores_the_cursor() {
+        let mut presence = presence(300f32);
+        let mut cursor = Cursor(100, 200);
+        assert_eq!(presence.update(false, &mut cursor), None);
+        cursor.move_to(5, 5);
+        assert_eq!(presence.update(true, &mut cursor), Some(vec2(100f32, 200f32)));
+        assert_eq!((cursor.0, cursor.1), (100, 200));
+        // Repeated presence changes nothing.
+        assert_eq!(presence.update(true, &mut cursor), None);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut presence = Presence::new(config::Presence {
+            enabled: false,
+            ..config::Presence::default()
+        });
+        let mut cursor = Cursor(100, 200);
+        assert_eq!(presence.update(false, &mut cursor), None);
+        assert!(presence.is_present());
+        presence.poll(Instant::now() + ms(1_000_000));
+        assert!(!presence.acted);
+    }
+}
@@ -0,0 +1,86 @@
+// Watches the configuration file and sends it to the pipeline whenever it
+// changes, so that pointing can be tuned without restarting.
+//
+// The directory is watched rather than the file, since editors tend to save
+// by writing a new file and moving it over the old one. Until the directory
+// exists, we wait for it to be created. Files that do not parse or validate
+// are reported and otherwise ignored.
+
+use inotify::{Inotify, WatchMask};
+
+use std::io;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus;
+use crate::config::{self, Config};
+use crate::inputs::{Event, Input, InputAction};
+
+// Whether to stop, after looking at the actions sent to the input.
+fn stopped(inbox: &Receiver<InputAction>) -> bool {
+    match inbox.try_recv() {
+        Ok(InputAction::Shutdown) | Err(TryRecvError::Disconnected) => true,
+        // Configuration changes are control inputs, which are sent anyway.
+        Ok(InputAction::Pause) | Ok(InputAction::Resume) | Err(TryRecvError::Empty) => false,
+    }
+}
+
+// How often actions are looked at while waiting for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+fn watch(output: &bus::Sender, inbox: &Receiver<InputAction>) -> io::Result<()> {
+    let path = config::path();
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no file name")),
+    };
+
+    while !dir.is_dir() {
+        if stopped(inbox) {
+            return Ok(());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let mut inotify = Inotify::init()?;
+    inotify.add_watch(
+        dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+    )?;
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        if stopped(inbox) {
+            return Ok(());
+        }
+
+        // Does not block, there just are no events if nothing changed.
+        let changed = inotify
+            .read_events(&mut buffer)?
+            .any(|event| event.name == Some(name));
+        if !changed {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        match Config::load() {
+            Ok(config) => {
+                println!("Reloaded {}", path.display());
+                if output
+                    .send(Event::now(Input::Config(Box::new(config))))
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            Err(e) => println!("Could not reload {}: {:?}", path.display(), e),
+        }
+    }
+}
+
+pub fn listen(output: bus::Sender, inbox: Receiver<InputAction>) {
+    if let Err(e) = watch(&output, &inbox) {
+        println!("Could not watch {}: {}", config::path().display(), e);
+    }
+}
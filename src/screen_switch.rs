@@ -0,0 +1,243 @@
+// Follows the user turning to monitors without an eye tracker.
+//
+// The eye tracker only covers the monitor it is mounted on. While it finds
+// gaze on that monitor, the head pose is taken as the pose of looking at it.
+// Once gaze is lost and the head points closer to another monitor, by more
+// than `hysteresis`, the cursor moves onto that monitor and is pointed with
+// the head alone. Gaze takes over again when the user looks back.
+//
+// The direction of each monitor, relative to the one with the eye tracker,
+// is estimated from the distance between their centers with
+// `degrees_per_px`, unless given in `directions`.
+
+use cgmath::prelude::*;
+use cgmath::{vec2, Vector2};
+
+use crate::config;
+use crate::head_pointer::angle_difference;
+use crate::screens::Layout;
+
+// Weight of each head pose sample while looking at the monitor with the eye
+// tracker, for smoothing out glances within it.
+const NEUTRAL_SMOOTHING: f32 = 0.05f32;
+
+pub struct Switcher {
+    config: config::ScreenSwitch,
+    // Direction [°] (yaw, pitch) of each monitor of the layout.
+    directions: Vec<Vector2<f32>>,
+    tracker: usize,
+    current: usize,
+    // Head pose [°] while looking at the monitor with the eye tracker.
+    neutral: Option<Vector2<f32>>,
+}
+
+impl Switcher {
+    pub fn new(config: config::ScreenSwitch, layout: &Layout) -> Switcher {
+        let tracker = layout.tracker();
+        let directions = layout
+            .screens
+            .iter()
+            .map(|screen| match config.directions.get(&screen.name) {
+                Some(&[yaw, pitch]) => vec2(yaw, pitch),
+                None => {
+                    let offset = screen.center() - tracker.center();
+                    // Turning right and looking up are positive.
+                    vec2(offset.x, -offset.y) * config.degrees_per_px
+                }
+            })
+            .collect();
+        let tracker = layout
+            .screens
+            .iter()
+            .position(|screen| screen.name == tracker.name)
+            .unwrap_or(0);
+        Switcher {
+            config,
+            directions,
+            tracker,
+            current: tracker,
+            neutral: None,
+        }
+    }
+
+    // Index of the monitor the user looks at.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    // Whether the user looks at a monitor without an eye tracker.
+    pub fn is_away(&self) -> bool {
+        self.current != self.tracker
+    }
+
+    // Direction [°] of the monitor the user looks at, relative to the one
+    // with the eye tracker.
+    pub fn direction(&self) -> Vector2<f32> {
+        self.directions[self.current]
+    }
+
+    // Takes the head pose [°] (yaw, pitch), if one arrived, and whether the
+    // eye tracker finds gaze on its monitor. Returns the index of the monitor
+    // the user turned to, if they did.
+    pub fn update(&mut self, pose: Option<Vector2<f32>>, looking: bool) -> Option<usize> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        if looking {
+            if let Some(pose) = pose {
+                let neutral = *self.neutral.get_or_insert(pose);
+                self.neutral = Some(
+                    neutral
+                        + vec2(
+                            angle_difference(pose.x, neutral.x),
+                            angle_difference(pose.y, neutral.y),
+                        ) * NEUTRAL_SMOOTHING,
+                );
+            }
+            return self.switch(self.tracker);
+        }
+
+        // Without a neutral pose, there is nothing to compare against.
+        let (pose, neutral) = match (pose, self.neutral) {
+            (Some(pose), Some(neutral)) => (pose, neutral),
+            _ => return None,
+        };
+        let offset = vec2(
+            angle_difference(pose.x, neutral.x),
+            angle_difference(pose.y, neutral.y),
+        );
+        let distance = |i: usize| (offset - self.directions[i]).magnitude();
+        let nearest = (0..self.directions.len())
+            .min_by(|&a, &b| distance(a).partial_cmp(&distance(b)).unwrap())?;
+        if distance(self.current) - distance(nearest) > self.config.hysteresis {
+            return self.switch(nearest);
+        }
+        None
+    }
+
+    fn switch(&mut self, index: usize) -> Option<usize> {
+        if index == self.current {
+            return None;
+        }
+        self.current = index;
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::screens::Screen;
+    use std::collections::BTreeMap;
+
+    fn monitor(name: &str, x: f32, y: f32, width: f32, height: f32) -> Screen {
+        Screen {
+            name: name.to_string(),
+            primary: false,
+            x,
+            y,
+            width,
+            height,
+            size_mm: None,
+        }
+    }
+
+    // A portrait monitor right of the one with the eye tracker, and a laptop
+    // below it. At 0.02°/px, the portrait one is 36.4° to the right and
+    // 4.8° down, the laptop 25.2° down.
+    fn layout() -> Layout {
+        Layout::with_tracker(
+            vec![
+                monitor("side", 2560f32, 0f32, 1080f32, 1920f32),
+                monitor("dell", 0f32, 0f32, 2560f32, 1440f32),
+                monitor("laptop", 320f32, 1440f32, 1920f32, 1080f32),
+            ],
+            1,
+        )
+    }
+
+    fn switcher(config: config::ScreenSwitch) -> Switcher {
+        Switcher::new(
+            config::ScreenSwitch {
+                enabled: true,
+                ..config
+            },
+            &layout(),
+        )
+    }
+
+    #[test]
+    fn switches_to_an_adjacent_monitor() {
+        let mut switcher = switcher(config::ScreenSwitch::default());
+        assert_eq!(switcher.current(), 1);
+        assert!(!switcher.is_away());
+
+        // Looking at the eye tracker's monitor gives the neutral pose.
+        assert_eq!(switcher.update(Some(vec2(10f32, 0f32)), true), None);
+        assert_eq!(switcher.update(Some(vec2(46f32, -5f32)), false), Some(0));
+        assert!(switcher.is_away());
+        let direction = switcher.direction();
+        assert!((direction - vec2(36.4f32, -4.8f32)).magnitude() < 1e-3);
+        // Staying there does not switch again.
+        assert_eq!(switcher.update(Some(vec2(44f32, -3f32)), false), None);
+
+        // Over to the laptop, then back to the eye tracker.
+        assert_eq!(switcher.update(Some(vec2(10f32, -25f32)), false), Some(2));
+        assert_eq!(switcher.update(None, true), Some(1));
+        assert!(!switcher.is_away());
+        assert_eq!(switcher.direction(), vec2(0f32, 0f32));
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut switcher = switcher(config::ScreenSwitch::default());
+        switcher.update(Some(vec2(0f32, 0f32)), true);
+        // Closer to the right monitor, but not by 10°.
+        assert_eq!(switcher.update(Some(vec2(22f32, -2f32)), false), None);
+        assert_eq!(switcher.update(Some(vec2(26f32, -2f32)), false), Some(0));
+        // On the way back, the right monitor is kept until the eye tracker's
+        // is closer by 10°.
+        assert_eq!(switcher.update(Some(vec2(14f32, -2f32)), false), None);
+        assert_eq!(switcher.update(Some(vec2(10f32, -2f32)), false), Some(1));
+    }
+
+    #[test]
+    fn needs_a_neutral_pose() {
+        let mut switcher = switcher(config::ScreenSwitch::default());
+        assert_eq!(switcher.update(Some(vec2(40f32, 0f32)), false), None);
+        assert_eq!(switcher.update(None, true), None);
+        assert_eq!(switcher.update(Some(vec2(40f32, 0f32)), false), None);
+    }
+
+    #[test]
+    fn across_the_seam() {
+        let mut switcher = switcher(config::ScreenSwitch::default());
+        switcher.update(Some(vec2(170f32, 0f32)), true);
+        assert_eq!(switcher.update(Some(vec2(-154f32, -5f32)), false), Some(0));
+    }
+
+    #[test]
+    fn configured_directions() {
+        let mut directions = BTreeMap::new();
+        directions.insert("laptop".to_string(), [0f32, -40f32]);
+        let mut switcher = switcher(config::ScreenSwitch {
+            directions,
+            ..config::ScreenSwitch::default()
+        });
+        switcher.update(Some(vec2(0f32, 0f32)), true);
+        // Where the laptop would have been estimated, nothing is close enough.
+        assert_eq!(switcher.update(Some(vec2(0f32, -24f32)), false), None);
+        assert_eq!(switcher.update(Some(vec2(0f32, -38f32)), false), Some(2));
+        assert_eq!(switcher.direction(), vec2(0f32, -40f32));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut switcher = Switcher::new(config::ScreenSwitch::default(), &layout());
+        switcher.update(Some(vec2(0f32, 0f32)), true);
+        assert_eq!(switcher.update(Some(vec2(36f32, -5f32)), false), None);
+        assert!(!switcher.is_away());
+    }
+}
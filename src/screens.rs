@@ -0,0 +1,344 @@
+// Layout of the monitors that make up the X screen.
+//
+// Gaze is mapped onto the monitor the eye tracker is mounted on. Head
+// movement may carry the cursor onto any other monitor, but not into areas of
+// the virtual desktop that no monitor shows (e.g. next to a smaller monitor).
+//
+// Unless configured, the eye tracker is taken to be on the monitor whose
+// physical size matches the display area set up on the tracker, or else on
+// the primary monitor.
+//
+// Monitors are read through RandR 1.5. Without it, the whole X screen is
+// taken to be a single monitor, and without an X server, the size given as
+// `fallback` is used.
+
+use cgmath::{vec2, Vector2};
+use x11rb::connection::Connection;
+use x11rb::protocol::randr::ConnectionExt as _;
+use x11rb::protocol::xproto::ConnectionExt as _;
+use x11rb::rust_connection::RustConnection;
+
+use std::error::Error;
+
+use crate::config;
+
+// Largest difference in width and height, relative to the display area of
+// the eye tracker, of a monitor it is taken to be mounted on.
+const DISPLAY_AREA_TOLERANCE: f32 = 0.1f32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Screen {
+    pub name: String,
+    pub primary: bool,
+    // Position and size [px] within the virtual desktop.
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    // Physical width and height [mm], if known.
+    pub size_mm: Option<[f32; 2]>,
+}
+
+impl Screen {
+    pub fn origin(&self) -> Vector2<f32> {
+        vec2(self.x, self.y)
+    }
+
+    pub fn size(&self) -> Vector2<f32> {
+        vec2(self.width, self.height)
+    }
+
+    pub fn center(&self) -> Vector2<f32> {
+        vec2(self.x + self.width / 2f32, self.y + self.height / 2f32)
+    }
+
+    pub fn contains(&self, p: Vector2<f32>) -> bool {
+        p.x >= self.x && p.x < self.x + self.width && p.y >= self.y && p.y < self.y + self.height
+    }
+
+    // Closest point [px] on the screen.
+    fn clamp(&self, p: Vector2<f32>) -> Vector2<f32> {
+        vec2(
+            p.x.max(self.x).min(self.x + self.width - 1f32),
+            p.y.max(self.y).min(self.y + self.height - 1f32),
+        )
+    }
+
+    // Position [px] on the virtual desktop of a normalized position on the
+    // screen, where (0, 0) is the top left corner.
+    pub fn denormalize(&self, p: Vector2<f32>) -> Vector2<f32> {
+        vec2(self.x + p.x * self.width, self.y + p.y * self.height)
+    }
+}
+
+pub struct Layout {
+    pub screens: Vec<Screen>,
+    // Index of the screen the eye tracker is mounted on.
+    tracker: usize,
+}
+
+fn size_mm(width: u32, height: u32) -> Option<[f32; 2]> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some([width as f32, height as f32])
+}
+
+fn monitors() -> Result<Vec<Screen>, Box<dyn Error>> {
+    let (conn, screen) = RustConnection::connect(None)?;
+    let root = &conn.setup().roots[screen];
+
+    let randr = match conn.randr_query_version(1, 5) {
+        Ok(cookie) => cookie
+            .reply()
+            .map(|r| (r.major_version, r.minor_version) >= (1, 5))
+            .unwrap_or(false),
+        Err(_) => false,
+    };
+    if !randr {
+        return Ok(vec![Screen {
+            name: "default".to_string(),
+            primary: true,
+            x: 0f32,
+            y: 0f32,
+            width: root.width_in_pixels as f32,
+            height: root.height_in_pixels as f32,
+            size_mm: size_mm(
+                root.width_in_millimeters as u32,
+                root.height_in_millimeters as u32,
+            ),
+        }]);
+    }
+
+    let reply = conn.randr_get_monitors(root.root, true)?.reply()?;
+    let mut screens = vec![];
+    for monitor in reply.monitors {
+        let name = conn.get_atom_name(monitor.name)?.reply()?.name;
+        screens.push(Screen {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            primary: monitor.primary,
+            x: monitor.x as f32,
+            y: monitor.y as f32,
+            width: monitor.width as f32,
+            height: monitor.height as f32,
+            size_mm: size_mm(monitor.width_in_millimeters, monitor.height_in_millimeters),
+        });
+    }
+    Ok(screens)
+}
+
+// Index of the monitor the eye tracker is mounted on, given the width and
+// height [mm] of its display area if known.
+fn tracker(screens: &[Screen], config: &config::Screens, display_area: Option<[f32; 2]>) -> usize {
+    if let Some(ref name) = config.tracker {
+        if let Some(index) = screens.iter().position(|s| &s.name == name) {
+            return index;
+        }
+        println!("No monitor named {}, using the default one.", name);
+    }
+
+    // Relative difference in size to the display area.
+    let mismatch = |screen: &Screen| -> Option<f32> {
+        let (area, size) = (display_area?, screen.size_mm?);
+        Some(((size[0] - area[0]) / area[0]).abs().max(((size[1] - area[1]) / area[1]).abs()))
+    };
+    let matching = screens
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| Some((i, mismatch(s)?)))
+        .filter(|&(_, mismatch)| mismatch <= DISPLAY_AREA_TOLERANCE)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    match matching {
+        Some((index, _)) => index,
+        None => screens.iter().position(|s| s.primary).unwrap_or(0),
+    }
+}
+
+impl Layout {
+    // Takes the width and height [mm] of the display area of the eye
+    // tracker, if known.
+    pub fn detect(config: &config::Screens, display_area: Option<[f32; 2]>) -> Layout {
+        let screens = match monitors() {
+            Ok(ref screens) if screens.is_empty() => None,
+            Ok(screens) => Some(screens),
+            Err(e) => {
+                println!("Could not read monitor layout: {}", e);
+                None
+            }
+        };
+        let screens = screens.unwrap_or_else(|| {
+            vec![Screen {
+                name: "fallback".to_string(),
+                primary: true,
+                x: 0f32,
+                y: 0f32,
+                width: config.fallback[0] as f32,
+                height: config.fallback[1] as f32,
+                size_mm: None,
+            }]
+        });
+
+        let tracker = tracker(&screens, config, display_area);
+        Layout { screens, tracker }
+    }
+
+    // Layout of the given monitors, with the eye tracker on `tracker`.
+    #[cfg(test)]
+    pub fn with_tracker(screens: Vec<Screen>, tracker: usize) -> Layout {
+        Layout { screens, tracker }
+    }
+
+    pub fn tracker(&self) -> &Screen {
+        &self.screens[self.tracker]
+    }
+
+    pub fn at(&self, p: Vector2<f32>) -> Option<&Screen> {
+        self.screens.iter().find(|s| s.contains(p))
+    }
+
+    // Closest point [px] that is shown on a monitor.
+    pub fn clamp(&self, p: Vector2<f32>) -> Vector2<f32> {
+        if self.at(p).is_some() {
+            return p;
+        }
+        self.screens
+            .iter()
+            .map(|s| s.clamp(p))
+            .min_by(|a, b| {
+                let (da, db) = (*a - p, *b - p);
+                (da.x * da.x + da.y * da.y)
+                    .partial_cmp(&(db.x * db.x + db.y * db.y))
+                    .unwrap()
+            })
+            .unwrap_or(p)
+    }
+}
+
+// Prints the monitors, marking the one gaze is mapped onto.
+pub fn list(config: &config::Screens) {
+    let layout = Layout::detect(config, None);
+    for (i, screen) in layout.screens.iter().enumerate() {
+        println!(
+            "{} {}x{}+{}+{}{}{}",
+            screen.name,
+            screen.width,
+            screen.height,
+            screen.x,
+            screen.y,
+            if screen.primary { " primary" } else { "" },
+            if i == layout.tracker { " tracker" } else { "" },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(name: &str, x: f32, width: f32, size_mm: Option<[f32; 2]>) -> Screen {
+        Screen {
+            name: name.to_string(),
+            primary: name == "primary",
+            x,
+            y: 0f32,
+            width,
+            height: 1440f32,
+            size_mm,
+        }
+    }
+
+    fn monitor(name: &str, x: f32, y: f32, width: f32, height: f32) -> Screen {
+        Screen {
+            name: name.to_string(),
+            primary: false,
+            x,
+            y,
+            width,
+            height,
+            size_mm: None,
+        }
+    }
+
+    // A monitor with a laptop centered below it, and one in portrait to the
+    // right. Left of the laptop and below the right one, nothing is shown.
+    fn layout() -> Layout {
+        Layout {
+            screens: vec![
+                monitor("dell", 0f32, 0f32, 2560f32, 1440f32),
+                monitor("laptop", 320f32, 1440f32, 1920f32, 1080f32),
+                monitor("side", 2560f32, 0f32, 1080f32, 1920f32),
+            ],
+            tracker: 0,
+        }
+    }
+
+    fn name(layout: &Layout, p: Vector2<f32>) -> Option<&str> {
+        layout.at(p).map(|s| s.name.as_str())
+    }
+
+    #[test]
+    fn finds_the_monitor() {
+        let layout = layout();
+        assert_eq!(layout.tracker().name, "dell");
+        assert_eq!(name(&layout, vec2(0f32, 0f32)), Some("dell"));
+        assert_eq!(name(&layout, vec2(2559f32, 1439f32)), Some("dell"));
+        assert_eq!(name(&layout, vec2(2560f32, 1439f32)), Some("side"));
+        assert_eq!(name(&layout, vec2(1000f32, 1440f32)), Some("laptop"));
+        assert_eq!(name(&layout, vec2(3000f32, 1919f32)), Some("side"));
+        // Gaps and outside of the desktop.
+        assert_eq!(name(&layout, vec2(100f32, 2000f32)), None);
+        assert_eq!(name(&layout, vec2(3000f32, 1920f32)), None);
+        assert_eq!(name(&layout, vec2(-1f32, 100f32)), None);
+    }
+
+    #[test]
+    fn denormalizes() {
+        let layout = layout();
+        let laptop = &layout.screens[1];
+        assert_eq!(laptop.denormalize(vec2(0f32, 0f32)), vec2(320f32, 1440f32));
+        assert_eq!(laptop.denormalize(vec2(0.5f32, 0.5f32)), laptop.center());
+        assert_eq!(laptop.denormalize(vec2(1f32, 1f32)), vec2(2240f32, 2520f32));
+    }
+
+    #[test]
+    fn clamps_to_the_nearest_monitor() {
+        let layout = layout();
+        // Shown points stay.
+        assert_eq!(layout.clamp(vec2(1000f32, 1000f32)), vec2(1000f32, 1000f32));
+        assert_eq!(layout.clamp(vec2(2000f32, 2500f32)), vec2(2000f32, 2500f32));
+        // Left of the laptop.
+        assert_eq!(layout.clamp(vec2(100f32, 2000f32)), vec2(320f32, 2000f32));
+        // Off the right edge and the top of the desktop.
+        assert_eq!(layout.clamp(vec2(5000f32, 100f32)), vec2(3639f32, 100f32));
+        assert_eq!(layout.clamp(vec2(1000f32, -50f32)), vec2(1000f32, 0f32));
+        // Below the right monitor, closer to the corner of the laptop.
+        assert_eq!(layout.clamp(vec2(3000f32, 3000f32)), vec2(2239f32, 2519f32));
+    }
+
+    #[test]
+    fn tracker_by_display_area() {
+        let screens = vec![
+            screen("laptop", 0f32, 1920f32, Some([344f32, 194f32])),
+            screen("primary", 1920f32, 2560f32, Some([597f32, 336f32])),
+            screen("projector", 4480f32, 1920f32, None),
+        ];
+        let config = config::Screens::default();
+
+        assert_eq!(tracker(&screens, &config, None), 1);
+        // The display area is measured a little differently than the size
+        // the monitor reports.
+        assert_eq!(tracker(&screens, &config, Some([340f32, 190f32])), 0);
+        assert_eq!(tracker(&screens, &config, Some([600f32, 340f32])), 1);
+        // Nothing fits.
+        assert_eq!(tracker(&screens, &config, Some([480f32, 270f32])), 1);
+
+        // A configured monitor wins, unless there is none by that name.
+        let mut config = config::Screens {
+            tracker: Some("projector".to_string()),
+            ..config::Screens::default()
+        };
+        assert_eq!(tracker(&screens, &config, Some([340f32, 190f32])), 2);
+        config.tracker = Some("unknown".to_string());
+        assert_eq!(tracker(&screens, &config, Some([340f32, 190f32])), 0);
+    }
+}
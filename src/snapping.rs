@@ -0,0 +1,333 @@
+// Snaps the cursor to clickable elements near the gaze.
+//
+// The eye tracker is accurate to about 1°, which is coarse compared to small
+// buttons. When gaze makes the cursor jump, it lands on the center of the
+// nearest clickable element within `radius` instead of the raw gaze point.
+//
+// Clickable elements are looked up through the accessibility tree (AT-SPI)
+// of the active window. Walking the tree takes many round trips, so it is
+// done on a thread of its own every `refresh` seconds, and snapping uses the
+// elements found last.
+
+use cgmath::{vec2, Vector2};
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+use dbus::Path;
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Target {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Target {
+    fn center(&self) -> Vector2<f32> {
+        vec2(self.x + self.width / 2f32, self.y + self.height / 2f32)
+    }
+
+    // Distance [px] from `p` to the closest point of the target.
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        let dx = (self.x - p.x).max(p.x - (self.x + self.width)).max(0f32);
+        let dy = (self.y - p.y).max(p.y - (self.y + self.height)).max(0f32);
+        (dx * dx + dy * dy).sqrt()
+    }
+}
+
+// Source of clickable elements on screen.
+pub trait Targets {
+    fn targets(&mut self) -> Result<Vec<Target>, Box<dyn Error>>;
+}
+
+pub struct Snapper {
+    config: config::Snapping,
+    targets: Arc<Mutex<Vec<Target>>>,
+}
+
+impl Snapper {
+    pub fn new(config: config::Snapping) -> Snapper {
+        let targets = Arc::new(Mutex::new(vec![]));
+        if config.enabled {
+            let shared = targets.clone();
+            let refresh = Duration::from_secs_f32(config.refresh);
+            thread::spawn(move || match AtSpi::new() {
+                Ok(provider) => refresh_targets(provider, shared, refresh),
+                Err(e) => println!("Could not connect to the accessibility bus: {}", e),
+            });
+        }
+        Snapper { config, targets }
+    }
+
+    // Center of the most likely target of a gaze at `gaze` [px], if any is
+    // close enough.
+    pub fn snap(&self, gaze: Vector2<f32>) -> Option<Vector2<f32>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let targets = self.targets.lock().unwrap();
+        nearest(&targets, gaze, self.config.radius).map(|target| target.center())
+    }
+}
+
+// The target closest to `gaze`. Among targets that contain it, the one whose
+// center is closest.
+fn nearest(targets: &[Target], gaze: Vector2<f32>, radius: f32) -> Option<Target> {
+    let score = |target: &Target| {
+        let center = target.center() - gaze;
+        (target.distance(gaze), center.x * center.x + center.y * center.y)
+    };
+    targets
+        .iter()
+        .filter(|target| target.distance(gaze) <= radius)
+        .min_by(|a, b| score(a).partial_cmp(&score(b)).unwrap())
+        .cloned()
+}
+
+fn refresh_targets<T: Targets>(
+    mut provider: T,
+    shared: Arc<Mutex<Vec<Target>>>,
+    refresh: Duration,
+) {
+    loop {
+        look_up(&mut provider, &shared);
+        thread::sleep(refresh);
+    }
+}
+
+// Replaces the shared targets with those on screen now. Without them, there
+// is nothing to snap to.
+fn look_up<T: Targets>(provider: &mut T, shared: &Mutex<Vec<Target>>) {
+    match provider.targets() {
+        Ok(targets) => *shared.lock().unwrap() = targets,
+        Err(e) => {
+            println!("Could not look up clickable elements: {}", e);
+            shared.lock().unwrap().clear();
+        }
+    }
+}
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// Bounds on walking the accessibility tree, since some applications expose
+// huge trees (e.g. spreadsheets).
+const MAX_DEPTH: usize = 32;
+const MAX_NODES: usize = 5000;
+
+// Elements larger than this [px] are containers rather than targets, even if
+// they can be clicked.
+const MAX_TARGET_SIZE: i32 = 400;
+
+// Bits in the state set of an accessible.
+const STATE_ACTIVE: u32 = 1;
+const STATE_SHOWING: u32 = 25;
+
+const ACCESSIBLE: &str = "org.a11y.atspi.Accessible";
+const ACTION: &str = "org.a11y.atspi.Action";
+const COMPONENT: &str = "org.a11y.atspi.Component";
+
+// Bus name and object path of an accessible.
+type Reference = (String, Path<'static>);
+
+fn has_state(states: &[u32], state: u32) -> bool {
+    states
+        .get((state / 32) as usize)
+        .map_or(false, |bits| bits & (1 << (state % 32)) != 0)
+}
+
+// Clickable elements of the active window, as reported by AT-SPI.
+pub struct AtSpi {
+    conn: Connection,
+}
+
+impl AtSpi {
+    pub fn new() -> Result<AtSpi, Box<dyn Error>> {
+        // Accessibility has a bus of its own, announced on the session bus.
+        let session = Connection::new_session()?;
+        let (address,): (String,) = session
+            .with_proxy("org.a11y.Bus", "/org/a11y/bus", TIMEOUT)
+            .method_call("org.a11y.Bus", "GetAddress", ())?;
+        let mut channel = Channel::open_private(&address)?;
+        channel.register()?;
+        Ok(AtSpi {
+            conn: Connection::from(channel),
+        })
+    }
+
+    fn call<R: dbus::arg::ReadAll, A: dbus::arg::AppendAll>(
+        &self,
+        object: &Reference,
+        interface: &str,
+        method: &str,
+        args: A,
+    ) -> Result<R, dbus::Error> {
+        self.conn
+            .with_proxy(object.0.as_str(), object.1.clone(), TIMEOUT)
+            .method_call(interface, method, args)
+    }
+
+    fn children(&self, object: &Reference) -> Result<Vec<Reference>, dbus::Error> {
+        let (children,): (Vec<Reference>,) = self.call(object, ACCESSIBLE, "GetChildren", ())?;
+        Ok(children)
+    }
+
+    fn states(&self, object: &Reference) -> Result<Vec<u32>, dbus::Error> {
+        let (states,): (Vec<u32>,) = self.call(object, ACCESSIBLE, "GetState", ())?;
+        Ok(states)
+    }
+
+    fn walk(&self, object: &Reference, depth: usize, nodes: &mut usize, out: &mut Vec<Target>) {
+        *nodes += 1;
+        if depth > MAX_DEPTH || *nodes > MAX_NODES {
+            return;
+        }
+        // Applications come and go while we walk, so errors only skip the
+        // element.
+        match self.states(object) {
+            Ok(states) if has_state(&states, STATE_SHOWING) => (),
+            _ => return,
+        }
+
+        let interfaces: Vec<String> = self
+            .call(object, ACCESSIBLE, "GetInterfaces", ())
+            .map(|(interfaces,): (Vec<String>,)| interfaces)
+            .unwrap_or_default();
+        let clickable = interfaces.iter().any(|i| i == ACTION)
+            && interfaces.iter().any(|i| i == COMPONENT);
+        if clickable {
+            // Coordinates relative to the screen.
+            let extents: Result<((i32, i32, i32, i32),), _> =
+                self.call(object, COMPONENT, "GetExtents", (0u32,));
+            if let Ok(((x, y, width, height),)) = extents {
+                let size = 1..=MAX_TARGET_SIZE;
+                if size.contains(&width) && size.contains(&height) {
+                    out.push(Target {
+                        x: x as f32,
+                        y: y as f32,
+                        width: width as f32,
+                        height: height as f32,
+                    });
+                }
+            }
+        }
+
+        for child in self.children(object).unwrap_or_default() {
+            self.walk(&child, depth + 1, nodes, out);
+        }
+    }
+}
+
+impl Targets for AtSpi {
+    fn targets(&mut self) -> Result<Vec<Target>, Box<dyn Error>> {
+        let root = (
+            "org.a11y.atspi.Registry".to_string(),
+            Path::from("/org/a11y/atspi/accessible/root"),
+        );
+        let mut targets = vec![];
+        let mut nodes = 0;
+        for application in self.children(&root)? {
+            for window in self.children(&application).unwrap_or_default() {
+                let active = self
+                    .states(&window)
+                    .map_or(false, |states| has_state(&states, STATE_ACTIVE));
+                if active {
+                    self.walk(&window, 0, &mut nodes, &mut targets);
+                }
+            }
+        }
+        Ok(targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fixed targets, or a failing lookup without any.
+    struct Mock(Option<Vec<Target>>);
+
+    impl Targets for Mock {
+        fn targets(&mut self) -> Result<Vec<Target>, Box<dyn Error>> {
+            self.0.clone().ok_or_else(|| "no accessibility bus".into())
+        }
+    }
+
+    fn target(x: f32, y: f32, width: f32, height: f32) -> Target {
+        Target {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // A snapper that snaps to the targets of `provider`, within 40 px.
+    fn snapper(mut provider: Mock) -> Snapper {
+        let snapper = Snapper {
+            config: config::Snapping {
+                enabled: true,
+                ..config::Snapping::default()
+            },
+            targets: Arc::new(Mutex::new(vec![])),
+        };
+        look_up(&mut provider, &snapper.targets);
+        snapper
+    }
+
+    #[test]
+    fn snaps_to_nearest_target() {
+        let snapper = snapper(Mock(Some(vec![
+            target(100f32, 100f32, 20f32, 20f32),
+            target(150f32, 100f32, 20f32, 20f32),
+        ])));
+        assert_eq!(snapper.snap(vec2(125f32, 110f32)), Some(vec2(110f32, 110f32)));
+        assert_eq!(snapper.snap(vec2(140f32, 110f32)), Some(vec2(160f32, 110f32)));
+    }
+
+    #[test]
+    fn prefers_closest_center_among_containing_targets() {
+        // A toolbar and a button on it.
+        let snapper = snapper(Mock(Some(vec![
+            target(0f32, 0f32, 300f32, 40f32),
+            target(200f32, 10f32, 20f32, 20f32),
+        ])));
+        assert_eq!(snapper.snap(vec2(205f32, 15f32)), Some(vec2(210f32, 20f32)));
+        assert_eq!(snapper.snap(vec2(100f32, 20f32)), Some(vec2(150f32, 20f32)));
+    }
+
+    #[test]
+    fn ignores_targets_beyond_radius() {
+        let snapper = snapper(Mock(Some(vec![target(100f32, 100f32, 20f32, 20f32)])));
+        assert_eq!(snapper.snap(vec2(160f32, 110f32)), Some(vec2(110f32, 110f32)));
+        assert_eq!(snapper.snap(vec2(161f32, 110f32)), None);
+        assert_eq!(snapper.snap(vec2(110f32, 50f32)), None);
+    }
+
+    #[test]
+    fn without_targets_gaze_is_kept() {
+        assert_eq!(snapper(Mock(Some(vec![]))).snap(vec2(10f32, 10f32)), None);
+        assert_eq!(snapper(Mock(None)).snap(vec2(10f32, 10f32)), None);
+    }
+
+    #[test]
+    fn failed_lookup_clears_targets() {
+        let mut snapper = snapper(Mock(Some(vec![target(0f32, 0f32, 20f32, 20f32)])));
+        assert!(snapper.snap(vec2(10f32, 10f32)).is_some());
+        look_up(&mut Mock(None), &snapper.targets);
+        assert_eq!(snapper.snap(vec2(10f32, 10f32)), None);
+
+        snapper.config.enabled = false;
+        look_up(
+            &mut Mock(Some(vec![target(0f32, 0f32, 20f32, 20f32)])),
+            &snapper.targets,
+        );
+        assert_eq!(snapper.snap(vec2(10f32, 10f32)), None);
+    }
+}
@@ -0,0 +1,240 @@
+// Latency of the stages an input goes through on its way to the cursor:
+//
+//  * `gaze device`, `head device`: From the timestamp of the device to the
+//    input being sent to the pipeline. For the IMU, this is relative to the
+//    fastest frame observed recently, see `clock::Clock`.
+//  * `queue`: From being sent to being received by the pipeline.
+//  * `processing`: Handling of the input in the pipeline, including output.
+//  * `output`: Moving the cursor through the output backend.
+//  * `gaze to cursor`: From the timestamp of a gaze sample to the cursor
+//    move it caused.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::output::{Button, Output};
+
+// Bucket `i` counts durations below 2^i µs, the last one everything else.
+const BUCKETS: usize = 24;
+
+#[derive(Clone)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: [0; BUCKETS],
+            count: 0,
+            sum: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+        }
+    }
+
+    fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+        self.max = self.max.max(duration);
+    }
+
+    fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(self.sum.as_secs_f64() / self.count as f64)
+    }
+
+    // Upper bound of the bucket that contains quantile `q`.
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return Duration::from_micros(1u64 << bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e3
+}
+
+pub struct Stats {
+    since: Instant,
+    stages: BTreeMap<&'static str, Histogram>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            since: Instant::now(),
+            stages: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, stage: &'static str, duration: Duration) {
+        self.stages
+            .entry(stage)
+            .or_insert_with(Histogram::new)
+            .record(duration);
+    }
+
+    // Table of all stages, with durations in milliseconds.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        writeln!(
+            report,
+            "over the last {}s:",
+            self.since.elapsed().as_secs()
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "{:<16} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            "stage [ms]", "count", "mean", "p50", "p90", "p99", "max"
+        )
+        .unwrap();
+        for (stage, histogram) in &self.stages {
+            writeln!(
+                report,
+                "{:<16} {:>8} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3}",
+                stage,
+                histogram.count,
+                millis(histogram.mean()),
+                millis(histogram.quantile(0.5)),
+                millis(histogram.quantile(0.9)),
+                millis(histogram.quantile(0.99)),
+                millis(histogram.max),
+            )
+            .unwrap();
+        }
+        report
+    }
+
+    pub fn reset(&mut self) {
+        *self = Stats::new();
+    }
+}
+
+pub type SharedStats = Arc<Mutex<Stats>>;
+
+// Measures how long the wrapped output takes to move the cursor.
+pub struct TimedOutput {
+    inner: Box<dyn Output>,
+    // Time spent moving since this was last taken.
+    pub elapsed: Option<Duration>,
+}
+
+impl TimedOutput {
+    pub fn new(inner: Box<dyn Output>) -> TimedOutput {
+        TimedOutput {
+            inner,
+            elapsed: None,
+        }
+    }
+
+    fn add(&mut self, start: Instant) {
+        let elapsed = start.elapsed() + self.elapsed.unwrap_or_default();
+        self.elapsed = Some(elapsed);
+    }
+}
+
+impl Output for TimedOutput {
+    fn move_to(&mut self, x: i32, y: i32) {
+        let start = Instant::now();
+        self.inner.move_to(x, y);
+        self.add(start);
+    }
+
+    fn move_relative(&mut self, dx: f32, dy: f32) {
+        let start = Instant::now();
+        self.inner.move_relative(dx, dy);
+        self.add(start);
+    }
+
+    fn location(&mut self) -> Option<(i32, i32)> {
+        self.inner.location()
+    }
+
+    fn press(&mut self, button: Button) {
+        self.inner.press(button);
+    }
+
+    fn release(&mut self, button: Button) {
+        self.inner.release(button);
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        self.inner.scroll(dx, dy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn us(micros: u64) -> Duration {
+        Duration::from_micros(micros)
+    }
+
+    fn bucket(micros: u64) -> usize {
+        let mut histogram = Histogram::new();
+        histogram.record(us(micros));
+        histogram.counts.iter().position(|&count| count == 1).unwrap()
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        // Powers of two start a bucket.
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(4), 3);
+        assert_eq!(bucket(1023), 10);
+        assert_eq!(bucket(1024), 11);
+        // Everything beyond the buckets lands in the last one.
+        assert_eq!(bucket(1 << 40), BUCKETS - 1);
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), us(0));
+        for _ in 0..98 {
+            histogram.record(us(100));
+        }
+        histogram.record(us(10_000));
+        histogram.record(us(12_000));
+
+        assert_eq!(histogram.count, 100);
+        assert_eq!(histogram.mean(), us(318));
+        assert_eq!(histogram.max, us(12_000));
+        // Upper bound of the bucket, 128 µs.
+        assert_eq!(histogram.quantile(0.5), us(128));
+        assert_eq!(histogram.quantile(0.98), us(128));
+        // The bucket reaches up to 16384 µs, but nothing took that long.
+        assert_eq!(histogram.quantile(0.99), us(12_000));
+    }
+
+    #[test]
+    fn reset() {
+        let mut stats = Stats::new();
+        stats.record("queue", us(100));
+        assert!(stats.report().contains("queue"));
+        stats.reset();
+        assert!(stats.stages.is_empty());
+        assert!(!stats.report().contains("queue"));
+    }
+}
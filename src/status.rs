@@ -0,0 +1,216 @@
+// Status of a running instance, served over a Unix socket.
+//
+// A client connects, sends a single line with a command and receives the
+// answer, after which the connection is closed. Commands:
+//  * `status`: The current `Status`, as TOML.
+//  * `stats`: Latency histograms, see `stats::Stats`.
+//  * `stats reset`: Clears the histograms.
+//  * `recenter`: Takes the current head pose as the neutral pose of absolute
+//    pointing.
+
+use serde::Serialize;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::{self, QueueStats};
+use crate::inputs::{Event, Health, Input, TrackerStreams};
+use crate::stats::SharedStats;
+
+// Number of events that are kept for display.
+const EVENTS: usize = 16;
+
+// How long a client may take to send its command.
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status {
+    pub present: Option<bool>,
+    pub tracker_connected: Option<bool>,
+    pub calibration_active: Option<bool>,
+    pub calibration_id: Option<u32>,
+    // Width and height [mm] of the display the eye tracker is mounted on.
+    pub display_area: Option<[f32; 2]>,
+    // Output frequency [Hz] of the eye tracker.
+    pub tracker_frequency: Option<f32>,
+    pub tracker_power_save: Option<bool>,
+    pub tracker_paused: Option<bool>,
+    pub gaze_stale: bool,
+    // Position of the user relative to the eye tracker, normalized such that
+    // (0.5, 0.5, 0.5) is optimal.
+    pub user_position: Option<[f32; 3]>,
+    // Most recent events, oldest first.
+    pub events: VecDeque<String>,
+    // Tables must come after all plain values to serialize as TOML.
+    // Optional streams of the eye tracker that are in use.
+    pub tracker_streams: Option<TrackerStreams>,
+    // Health of every input source.
+    pub sources: BTreeMap<&'static str, Health>,
+    // Events dropped or coalesced by the event bus, per source.
+    pub queues: BTreeMap<&'static str, QueueStats>,
+}
+
+impl Status {
+    pub fn event(&mut self, event: String) {
+        println!("{}", event);
+        if self.events.len() == EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+pub fn socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("glimpse.sock"),
+        None => env::temp_dir().join(format!(
+            "glimpse-{}.sock",
+            env::var("USER").unwrap_or_default()
+        )),
+    }
+}
+
+fn answer(
+    stream: UnixStream,
+    status: &SharedStatus,
+    stats: &SharedStats,
+    output: &bus::Sender,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut command = String::new();
+    // Another instance checking whether we run closes without a command.
+    if BufReader::new(&stream).read_line(&mut command)? == 0 {
+        return Ok(());
+    }
+
+    let response = match command.trim() {
+        "status" => {
+            let status = status.lock().unwrap().clone();
+            toml::to_string(&status).unwrap_or_else(|e| format!("error: {}\n", e))
+        }
+        "stats" => stats.lock().unwrap().report(),
+        "stats reset" => {
+            stats.lock().unwrap().reset();
+            String::new()
+        }
+        "recenter" => match output.send(Event::now(Input::Recenter)) {
+            Ok(()) => String::new(),
+            Err(_) => "error: not running\n".to_string(),
+        },
+        other => format!("error: unknown command {:?}\n", other),
+    };
+
+    (&stream).write_all(response.as_bytes())
+}
+
+// Binds the socket at `path`, unless another instance is serving it.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another instance is running",
+            ))
+        }
+        // A previous instance left its socket behind.
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    UnixListener::bind(path)
+}
+
+pub fn serve(status: SharedStatus, stats: SharedStats, output: bus::Sender) {
+    let path = socket_path();
+    let listener = match bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Could not bind {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Status client failed: {}", e);
+                    continue;
+                }
+            };
+            // Each client gets a thread, so that a slow one does not hold up
+            // the others.
+            let (status, stats, output) = (status.clone(), stats.clone(), output.clone());
+            thread::spawn(move || {
+                if let Err(e) = answer(stream, &status, &stats, &output) {
+                    println!("Status client failed: {}", e);
+                }
+            });
+        }
+    });
+}
+
+// Sends `command` to a running instance and returns the answer.
+pub fn request(command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path())?;
+    writeln!(stream, "{}", command)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stats::Stats;
+
+    // Answers `command` as a client connected to the socket would get it.
+    fn ask(command: &str, stats: &SharedStats) -> String {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let rx = bus::new();
+        writeln!(client, "{}", command).unwrap();
+        let status = Arc::new(Mutex::new(Status::default()));
+        answer(server, &status, stats, &rx.sender("status")).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn takes_over_a_stale_socket() {
+        let path = env::temp_dir().join(format!("glimpse-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let listener = bind(&path).unwrap();
+        // The socket is in use.
+        let e = bind(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+
+        // Its listener is gone, but the file is left behind.
+        drop(listener);
+        assert!(path.exists());
+        let listener = bind(&path).unwrap();
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stats_reset() {
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        stats.lock().unwrap().record("queue", Duration::from_micros(100));
+        assert!(ask("stats", &stats).contains("queue"));
+        assert_eq!(ask("stats reset", &stats), "");
+        assert!(!ask("stats", &stats).contains("queue"));
+    }
+}
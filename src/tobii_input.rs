@@ -1,17 +1,54 @@
 use tobii_sys::*;
 
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
 use std::ffi::{CStr, CString};
 use std::mem;
 use std::os::raw;
 use std::ptr;
-use std::sync::mpsc::{Receiver, SyncSender};
+use std::slice;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-use crate::inputs::{Input, InputAction};
+use crate::bus;
+use crate::clock::Clock;
+use crate::config::{self, Eye};
+use crate::inputs::{self, Class, Event, Input, InputAction, TrackerNotification, TrackerStreams};
 
 use tobii_sys::helpers::{self, status_to_result, PtrWrapper, TobiiError};
 
 struct CallbackContext {
-    output: SyncSender<Input>,
+    output: bus::Sender,
+    // Maps the system clock of the stream engine, which timestamps are in
+    // after time synchronization, to the host clock.
+    clock: RefCell<Clock>,
+    eye: Eye,
+    // Whether the selected eye was found in the most recent gaze origin. Only
+    // relevant if gaze is not reported per eye.
+    eye_tracked: Cell<bool>,
+    // Validity of the left and right eye in the most recent gaze origin.
+    validity: Cell<Option<(bool, bool)>>,
+    // Set when the tracker was calibrated, so the new calibration can be
+    // retrieved outside of the callback.
+    calibration_changed: Cell<bool>,
+    // Samples are dropped while paused, control inputs are still sent.
+    paused: Cell<bool>,
+}
+
+impl CallbackContext {
+    fn send(&self, timestamp_us: i64, input: Input) {
+        if self.paused.get() && !matches!(input.class(), Class::Control) {
+            return;
+        }
+        let time = self
+            .clock
+            .borrow()
+            .to_host(timestamp_us)
+            .unwrap_or_else(Instant::now);
+        // Nobody may be listening any more during shutdown. This runs in
+        // callbacks from C, where a panic would abort the process.
+        let _ = self.output.send(Event::new(time, input));
+    }
 }
 
 unsafe extern "C" fn custom_log_fn(
@@ -23,7 +60,7 @@ unsafe extern "C" fn custom_log_fn(
         return;
     }
     let s = CStr::from_ptr(text);
-    println!("LOG {}: {}", level, s.to_str().unwrap());
+    println!("LOG {}: {}", level, s.to_string_lossy());
 }
 
 unsafe extern "C" fn gaze_callback(
@@ -37,12 +74,39 @@ unsafe extern "C" fn gaze_callback(
         //println!("INVALID {}", pt.timestamp_us);
         return;
     }
+    // Combined gaze is biased if the selected eye is not tracked.
+    if context.eye != Eye::Both && !context.eye_tracked.get() {
+        return;
+    }
     let event = Input::TobiiGaze {
         x: pt.position_xy[0],
         y: pt.position_xy[1],
     };
     //signpost::trace(2, &[0, 0, 0, signpost::Color::Red as usize]);
-    context.output.send(event).unwrap();
+    context.send(pt.timestamp_us, event);
+}
+
+// Gaze per eye, only subscribed to if a single eye is selected.
+unsafe extern "C" fn gaze_data_callback(
+    gaze_data: *const GazeData,
+    user_data: *mut ::std::os::raw::c_void,
+) {
+    assert_ne!(user_data, ptr::null_mut());
+    let context = &*(user_data as *mut CallbackContext);
+    let data = &*gaze_data;
+    let eye = match context.eye {
+        Eye::Left => &data.left,
+        Eye::Right => &data.right,
+        Eye::Both => return,
+    };
+    if eye.gaze_point_validity != TOBII_VALIDITY_VALID {
+        return;
+    }
+    let event = Input::TobiiGaze {
+        x: eye.gaze_point_on_display_normalized_xy[0],
+        y: eye.gaze_point_on_display_normalized_xy[1],
+    };
+    context.send(data.timestamp_system_us, event);
 }
 
 unsafe extern "C" fn head_pose_callback(
@@ -66,7 +130,7 @@ unsafe extern "C" fn head_pose_callback(
         rz: pt.rotation_xyz[2],
     };
     //signpost::trace(2, &[0, 0, 0, signpost::color::red as usize]);
-    context.output.send(event).unwrap();
+    context.send(pt.timestamp_us, event);
 }
 
 unsafe extern "C" fn gaze_origin_callback(
@@ -76,12 +140,48 @@ unsafe extern "C" fn gaze_origin_callback(
     assert_ne!(user_data, ptr::null_mut());
     let context = &*(user_data as *mut CallbackContext);
     let pt = &*gaze_origin;
-    // todo: check other three validities
+    let left = pt.left_validity == TOBII_VALIDITY_VALID;
+    let right = pt.right_validity == TOBII_VALIDITY_VALID;
+    if context.validity.replace(Some((left, right))) != Some((left, right)) {
+        context.send(pt.timestamp_us, Input::TobiiEyeValidity { left, right });
+    }
+    // With a single eye selected, the other one may well be invalid, so the
+    // selected eye stands in for both.
+    let (l, r) = match context.eye {
+        Eye::Both if left && right => (pt.left_xyz, pt.right_xyz),
+        Eye::Left if left => (pt.left_xyz, pt.left_xyz),
+        Eye::Right if right => (pt.right_xyz, pt.right_xyz),
+        _ => {
+            //println!("invalid {}", pt.timestamp_us);
+            context.eye_tracked.set(false);
+            return;
+        }
+    };
+    context.eye_tracked.set(true);
+    let event = Input::TobiiGazeOrigin {
+        rx: r[0],
+        ry: r[1],
+        rz: r[2],
+
+        lx: l[0],
+        ly: l[1],
+        lz: l[2],
+    };
+    //signpost::trace(2, &[0, 0, 0, signpost::color::red as usize]);
+    context.send(pt.timestamp_us, event);
+}
+
+unsafe extern "C" fn eye_position_callback(
+    eye_position: *const EyePositionNormalized,
+    user_data: *mut ::std::os::raw::c_void,
+) {
+    assert_ne!(user_data, ptr::null_mut());
+    let context = &*(user_data as *mut CallbackContext);
+    let pt = &*eye_position;
     if pt.right_validity != TOBII_VALIDITY_VALID || pt.left_validity != TOBII_VALIDITY_VALID {
-        //println!("invalid {}", pt.timestamp_us);
         return;
     }
-    let event = Input::TobiiGazeOrigin {
+    let event = Input::TobiiEyePosition {
         rx: pt.right_xyz[0],
         ry: pt.right_xyz[1],
         rz: pt.right_xyz[2],
@@ -90,106 +190,642 @@ unsafe extern "C" fn gaze_origin_callback(
         ly: pt.left_xyz[1],
         lz: pt.left_xyz[2],
     };
-    //signpost::trace(2, &[0, 0, 0, signpost::color::red as usize]);
-    context.output.send(event).unwrap();
+    context.send(pt.timestamp_us, event);
 }
 
-unsafe fn input_loop(
-    output: SyncSender<Input>,
-    inbox: Receiver<InputAction>,
+unsafe extern "C" fn user_position_guide_callback(
+    guide: *const UserPositionGuide,
+    user_data: *mut ::std::os::raw::c_void,
+) {
+    assert_ne!(user_data, ptr::null_mut());
+    let context = &*(user_data as *mut CallbackContext);
+    let pt = &*guide;
+    if pt.right_position_validity != TOBII_VALIDITY_VALID
+        || pt.left_position_validity != TOBII_VALIDITY_VALID
+    {
+        return;
+    }
+    let event = Input::TobiiUserPosition {
+        rx: pt.right_position_normalized_xyz[0],
+        ry: pt.right_position_normalized_xyz[1],
+        rz: pt.right_position_normalized_xyz[2],
+
+        lx: pt.left_position_normalized_xyz[0],
+        ly: pt.left_position_normalized_xyz[1],
+        lz: pt.left_position_normalized_xyz[2],
+    };
+    context.send(pt.timestamp_us, event);
+}
+
+unsafe extern "C" fn presence_callback(
+    status: UserPresenceStatus,
+    timestamp_us: i64,
+    user_data: *mut ::std::os::raw::c_void,
+) {
+    assert_ne!(user_data, ptr::null_mut());
+    let context = &*(user_data as *mut CallbackContext);
+    let present = match status {
+        TOBII_USER_PRESENCE_STATUS_PRESENT => true,
+        TOBII_USER_PRESENCE_STATUS_AWAY => false,
+        _ => return,
+    };
+    context.send(timestamp_us, Input::TobiiPresence { present });
+}
+
+fn display_area(area: &DisplayArea) -> inputs::DisplayArea {
+    inputs::DisplayArea {
+        top_left: area.top_left_mm_xyz,
+        top_right: area.top_right_mm_xyz,
+        bottom_left: area.bottom_left_mm_xyz,
+    }
+}
+
+unsafe extern "C" fn notifications_callback(
+    notification: *const Notification,
+    user_data: *mut ::std::os::raw::c_void,
+) {
+    assert_ne!(user_data, ptr::null_mut());
+    let context = &*(user_data as *mut CallbackContext);
+    let n = &*notification;
+    let notification = match n.type_ {
+        TOBII_NOTIFICATION_TYPE_CALIBRATION_STATE_CHANGED => {
+            TrackerNotification::CalibrationStateChanged {
+                active: n.value.state == TOBII_STATE_BOOL_TRUE,
+            }
+        }
+        TOBII_NOTIFICATION_TYPE_CALIBRATION_ID_CHANGED => {
+            context.calibration_changed.set(true);
+            TrackerNotification::CalibrationIdChanged { id: n.value.uint_ }
+        }
+        TOBII_NOTIFICATION_TYPE_DISPLAY_AREA_CHANGED => {
+            TrackerNotification::DisplayAreaChanged(display_area(&n.value.display_area))
+        }
+        TOBII_NOTIFICATION_TYPE_FRAMERATE_CHANGED => TrackerNotification::FramerateChanged {
+            frequency: n.value.float_,
+        },
+        TOBII_NOTIFICATION_TYPE_POWER_SAVE_STATE_CHANGED => {
+            TrackerNotification::PowerSaveStateChanged {
+                active: n.value.state == TOBII_STATE_BOOL_TRUE,
+            }
+        }
+        TOBII_NOTIFICATION_TYPE_DEVICE_PAUSED_STATE_CHANGED => {
+            TrackerNotification::DevicePausedStateChanged {
+                paused: n.value.state == TOBII_STATE_BOOL_TRUE,
+            }
+        }
+        TOBII_NOTIFICATION_TYPE_FAULTS_CHANGED => TrackerNotification::FaultsChanged {
+            faults: CStr::from_ptr(n.value.string_.as_ptr())
+                .to_string_lossy()
+                .into_owned(),
+        },
+        TOBII_NOTIFICATION_TYPE_WARNINGS_CHANGED => TrackerNotification::WarningsChanged {
+            warnings: CStr::from_ptr(n.value.string_.as_ptr())
+                .to_string_lossy()
+                .into_owned(),
+        },
+        _ => return,
+    };
+    // Notifications carry no timestamp.
+    let _ = context
+        .output
+        .send(Event::now(Input::TobiiNotification(notification)));
+}
+
+// Reports display area and output frequency, which are otherwise only
+// reported when they change.
+unsafe fn send_initial_state(device: *mut Device, output: &bus::Sender) {
+    let mut area: DisplayArea = mem::zeroed();
+    // Not all trackers have a display area, e.g. before one was configured.
+    if status_to_result(tobii_get_display_area(device, &mut area as *mut _)).is_ok() {
+        let _ = output.send(Event::now(Input::TobiiNotification(
+            TrackerNotification::DisplayAreaChanged(display_area(&area)),
+        )));
+    }
+
+    let mut frequency = 0f32;
+    // Not all trackers support querying the output frequency.
+    if status_to_result(tobii_get_output_frequency(device, &mut frequency as *mut _)).is_ok() {
+        let _ = output.send(Event::now(Input::TobiiNotification(
+            TrackerNotification::FramerateChanged { frequency },
+        )));
+    }
+}
+
+// Streams that are subscribed to if the tracker supports them, in addition
+// to one of gaze point or gaze data.
+const SUBSCRIBED: &[Stream] = &[
+    TOBII_STREAM_GAZE_ORIGIN,
+    TOBII_STREAM_EYE_POSITION_NORMALIZED,
+    TOBII_STREAM_USER_PRESENCE,
+    TOBII_STREAM_HEAD_POSE,
+    TOBII_STREAM_USER_POSITION_GUIDE,
+];
+
+unsafe fn subscribe(
+    device: *mut Device,
+    stream: Stream,
+    user_data: *mut raw::c_void,
 ) -> Result<(), TobiiError> {
-    let custom_log = CustomLog {
-        log_context: ptr::null_mut(),
-        log_func: Some(custom_log_fn),
+    status_to_result(match stream {
+        TOBII_STREAM_GAZE_POINT => tobii_gaze_point_subscribe(device, Some(gaze_callback), user_data),
+        TOBII_STREAM_GAZE_DATA => {
+            tobii_gaze_data_subscribe(device, Some(gaze_data_callback), user_data)
+        }
+        TOBII_STREAM_GAZE_ORIGIN => {
+            tobii_gaze_origin_subscribe(device, Some(gaze_origin_callback), user_data)
+        }
+        TOBII_STREAM_EYE_POSITION_NORMALIZED => tobii_eye_position_normalized_subscribe(
+            device,
+            Some(eye_position_callback),
+            user_data,
+        ),
+        TOBII_STREAM_USER_PRESENCE => {
+            tobii_user_presence_subscribe(device, Some(presence_callback), user_data)
+        }
+        TOBII_STREAM_HEAD_POSE => {
+            tobii_head_pose_subscribe(device, Some(head_pose_callback), user_data)
+        }
+        TOBII_STREAM_USER_POSITION_GUIDE => tobii_user_position_guide_subscribe(
+            device,
+            Some(user_position_guide_callback),
+            user_data,
+        ),
+        _ => TOBII_ERROR_NOT_SUPPORTED,
+    })
+}
+
+unsafe fn unsubscribe(device: *mut Device, stream: Stream) {
+    let _ = match stream {
+        TOBII_STREAM_GAZE_POINT => tobii_gaze_point_unsubscribe(device),
+        TOBII_STREAM_GAZE_DATA => tobii_gaze_data_unsubscribe(device),
+        TOBII_STREAM_GAZE_ORIGIN => tobii_gaze_origin_unsubscribe(device),
+        TOBII_STREAM_EYE_POSITION_NORMALIZED => tobii_eye_position_normalized_unsubscribe(device),
+        TOBII_STREAM_USER_PRESENCE => tobii_user_presence_unsubscribe(device),
+        TOBII_STREAM_HEAD_POSE => tobii_head_pose_unsubscribe(device),
+        TOBII_STREAM_USER_POSITION_GUIDE => tobii_user_position_guide_unsubscribe(device),
+        _ => return,
     };
+}
 
-    println!("Initializing API!");
+// Unsubscribes from all streams when dropped, which must happen before the
+// device is destroyed.
+struct Subscriptions {
+    device: *mut Device,
+    streams: Vec<Stream>,
+    notifications: bool,
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for stream in &self.streams {
+            unsafe { unsubscribe(self.device, *stream) };
+        }
+        if self.notifications {
+            unsafe { tobii_notifications_unsubscribe(self.device) };
+        }
+    }
+}
+
+// Subscribes to all supported streams and notifications, and reports the
+// state of the tracker. Used on connecting and after every reconnect.
+unsafe fn connect(
+    device: *mut Device,
+    user_data: *mut raw::c_void,
+    eye: Eye,
+    output: &bus::Sender,
+) -> Result<Subscriptions, TobiiError> {
+    let mut subscriptions = Subscriptions {
+        device,
+        streams: vec![],
+        notifications: false,
+    };
+
+    // Gaze is essential, so do not rely on probing for gaze point.
+    let gaze = if eye != Eye::Both && stream_supported(device, TOBII_STREAM_GAZE_DATA) {
+        TOBII_STREAM_GAZE_DATA
+    } else {
+        if eye != Eye::Both {
+            println!(
+                "Tracker does not report gaze per eye, using combined gaze while the {} eye is tracked.",
+                format!("{:?}", eye).to_lowercase()
+            );
+        }
+        TOBII_STREAM_GAZE_POINT
+    };
+    subscribe(device, gaze, user_data)?;
+    subscriptions.streams.push(gaze);
+
+    for &stream in SUBSCRIBED {
+        if !stream_supported(device, stream) {
+            continue;
+        }
+        subscribe(device, stream, user_data)?;
+        subscriptions.streams.push(stream);
+    }
+
+    println!(
+        "Subscribed to {}.",
+        STREAMS
+            .iter()
+            .filter(|(stream, _)| subscriptions.streams.contains(stream))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    status_to_result(tobii_notifications_subscribe(
+        device,
+        Some(notifications_callback),
+        user_data,
+    ))?;
+    subscriptions.notifications = true;
+
+    let streams = TrackerStreams {
+        gaze_origin: subscriptions.streams.contains(&TOBII_STREAM_GAZE_ORIGIN),
+        eye_position: subscriptions.streams.contains(&TOBII_STREAM_EYE_POSITION_NORMALIZED),
+        presence: subscriptions.streams.contains(&TOBII_STREAM_USER_PRESENCE),
+        head_pose: subscriptions.streams.contains(&TOBII_STREAM_HEAD_POSE),
+        user_position_guide: subscriptions.streams.contains(&TOBII_STREAM_USER_POSITION_GUIDE),
+    };
+    let _ = output.send(Event::now(Input::TobiiConnection { connected: true }));
+    let _ = output.send(Event::now(Input::TobiiStreams(streams)));
+
+    send_initial_state(device, output);
+    Ok(subscriptions)
+}
+
+unsafe extern "C" fn calibration_receiver(
+    data: *const raw::c_void,
+    size: usize,
+    user_data: *mut raw::c_void,
+) {
+    let calibration = &mut *(user_data as *mut Vec<u8>);
+    *calibration = slice::from_raw_parts(data as *const u8, size).to_vec();
+}
+
+// Calibration currently in use by the tracker, to restore it after a
+// reconnect.
+unsafe fn retrieve_calibration(device: *mut Device) -> Option<Vec<u8>> {
+    let mut calibration: Vec<u8> = vec![];
+    let status = tobii_calibration_retrieve(
+        device,
+        Some(calibration_receiver),
+        &mut calibration as *mut Vec<u8> as *mut raw::c_void,
+    );
+    match status_to_result(status) {
+        Ok(()) if !calibration.is_empty() => Some(calibration),
+        Ok(()) => None,
+        Err(e) => {
+            println!("Could not retrieve calibration: {:?}", e);
+            None
+        }
+    }
+}
+
+unsafe fn apply_calibration(device: *mut Device, calibration: &[u8]) {
+    let status = tobii_calibration_apply(
+        device,
+        calibration.as_ptr() as *const raw::c_void,
+        calibration.len(),
+    );
+    if let Err(e) = status_to_result(status) {
+        println!("Could not apply calibration: {:?}", e);
+    }
+}
+
+// How often the clocks of tracker and host are synchronized. Tobii
+// recommends about every 30 seconds.
+const TIMESYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// Synchronizes the clock of the tracker with the system clock of the stream
+// engine, which timestamps are expressed in, and relates the latter to the
+// host clock.
+unsafe fn timesync(api: *mut Api, device: *mut Device, clock: &RefCell<Clock>) {
+    if let Err(e) = status_to_result(tobii_update_timesync(device)) {
+        println!("Could not synchronize clocks: {:?}", e);
+    }
+    let mut now_us = 0i64;
+    match status_to_result(tobii_system_clock(api, &mut now_us as *mut _)) {
+        Ok(()) => clock.borrow_mut().observe(now_us, Instant::now()),
+        Err(e) => println!("Could not read system clock: {:?}", e),
+    }
+}
+
+// Delay before the first attempt to reconnect. It is doubled after every
+// failed attempt, up to `RECONNECT_MAX`.
+const RECONNECT_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+// How often to look for a matching device when waiting for one.
+const DEVICE_POLL: Duration = Duration::from_secs(2);
+
+const STREAMS: &[(Stream, &str)] = &[
+    (TOBII_STREAM_GAZE_POINT, "gaze point"),
+    (TOBII_STREAM_GAZE_ORIGIN, "gaze origin"),
+    (TOBII_STREAM_EYE_POSITION_NORMALIZED, "eye position normalized"),
+    (TOBII_STREAM_USER_PRESENCE, "user presence"),
+    (TOBII_STREAM_HEAD_POSE, "head pose"),
+    (TOBII_STREAM_GAZE_DATA, "gaze data"),
+    (TOBII_STREAM_USER_POSITION_GUIDE, "user position guide"),
+];
+
+const CAPABILITIES: &[(Capability, &str)] = &[
+    (TOBII_CAPABILITY_DISPLAY_AREA_WRITABLE, "display area writable"),
+    (TOBII_CAPABILITY_CALIBRATION_2D, "calibration 2d"),
+    (TOBII_CAPABILITY_CALIBRATION_3D, "calibration 3d"),
+    (TOBII_CAPABILITY_PERSISTENT_STORAGE, "persistent storage"),
+    (TOBII_CAPABILITY_CALIBRATION_PER_EYE, "calibration per eye"),
+];
+
+pub struct TrackerInfo {
+    pub url: String,
+    pub serial_number: String,
+    pub model: String,
+    pub generation: String,
+    pub firmware_version: String,
+    pub streams: Vec<&'static str>,
+    pub capabilities: Vec<&'static str>,
+}
+
+impl TrackerInfo {
+    // Whether the tracker is the one described by `selector`, which may be
+    // its URL, serial number or model.
+    fn matches(&self, selector: &str) -> bool {
+        self.url == selector || self.serial_number == selector || self.model == selector
+    }
+}
+
+fn c_string(chars: &[raw::c_char]) -> String {
+    unsafe { CStr::from_ptr(chars.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+unsafe fn stream_supported(device: *mut Device, stream: Stream) -> bool {
+    let mut supported: Supported = mem::zeroed();
+    status_to_result(tobii_stream_supported(device, stream, &mut supported as *mut _)).is_ok()
+        && supported == TOBII_SUPPORTED
+}
+
+unsafe fn capability_supported(device: *mut Device, capability: Capability) -> bool {
+    let mut supported: Supported = mem::zeroed();
+    status_to_result(tobii_capability_supported(
+        device,
+        capability,
+        &mut supported as *mut _,
+    ))
+    .is_ok()
+        && supported == TOBII_SUPPORTED
+}
+
+unsafe fn create_api(custom_log: &CustomLog) -> Result<*mut Api, TobiiError> {
     let mut api_ptr: *mut Api = mem::zeroed();
     let status = tobii_api_create(
         &mut api_ptr as *mut *mut Api,
         ptr::null_mut(),
-        &custom_log as *const _,
+        custom_log as *const _,
     );
     status_to_result(status)?;
-    let api = PtrWrapper::new(api_ptr, tobii_api_destroy);
+    Ok(api_ptr)
+}
 
-    let devices = helpers::list_devices(api.ptr())?;
-    println!("{:?}", devices);
+unsafe fn create_device(api: *mut Api, url: &str) -> Result<*mut Device, TobiiError> {
+    let url_c_string = CString::new(url).unwrap();
+    let url_c = url_c_string.as_c_str();
+    let mut device_ptr: *mut Device = mem::zeroed();
+    let status = tobii_device_create(api, url_c.as_ptr(), &mut device_ptr as *mut *mut Device);
+    status_to_result(status)?;
+    Ok(device_ptr)
+}
+
+unsafe fn tracker_info(api: *mut Api, url: &str) -> Result<TrackerInfo, TobiiError> {
+    let device = PtrWrapper::new(create_device(api, url)?, tobii_device_destroy);
+
+    let mut info: DeviceInfo = mem::zeroed();
+    status_to_result(tobii_get_device_info(device.ptr(), &mut info as *mut _))?;
+
+    Ok(TrackerInfo {
+        url: url.to_string(),
+        serial_number: c_string(&info.serial_number),
+        model: c_string(&info.model),
+        generation: c_string(&info.generation),
+        firmware_version: c_string(&info.firmware_version),
+        streams: STREAMS
+            .iter()
+            .filter(|(stream, _)| stream_supported(device.ptr(), *stream))
+            .map(|(_, name)| *name)
+            .collect(),
+        capabilities: CAPABILITIES
+            .iter()
+            .filter(|(capability, _)| capability_supported(device.ptr(), *capability))
+            .map(|(_, name)| *name)
+            .collect(),
+    })
+}
+
+// URL of the tracker described by `selector`, or of the first tracker if
+// there is no selector.
+unsafe fn select_device(api: *mut Api, selector: Option<&str>) -> Result<Option<String>, TobiiError> {
+    let devices = helpers::list_devices(api)?;
+
+    let selector = match selector {
+        Some(selector) => selector,
+        None => return Ok(devices.into_iter().next()),
+    };
+
+    if devices.iter().any(|url| url == selector) {
+        return Ok(Some(selector.to_string()));
+    }
+
+    for url in devices {
+        match tracker_info(api, &url) {
+            Ok(info) => {
+                if info.matches(selector) {
+                    return Ok(Some(url));
+                }
+            }
+            Err(e) => println!("Could not query {}: {:?}", url, e),
+        }
+    }
+    Ok(None)
+}
+
+unsafe fn print_devices() -> Result<(), TobiiError> {
+    let custom_log = CustomLog {
+        log_context: ptr::null_mut(),
+        log_func: Some(custom_log_fn),
+    };
+    let api = PtrWrapper::new(create_api(&custom_log)?, tobii_api_destroy);
 
-    if devices.len() < 1 {
+    let devices = helpers::list_devices(api.ptr())?;
+    if devices.is_empty() {
         println!("No devices");
-        return Ok(());
     }
+    for url in devices {
+        match tracker_info(api.ptr(), &url) {
+            Ok(info) => {
+                println!("{}", info.url);
+                println!("  serial number:    {}", info.serial_number);
+                println!("  model:            {}", info.model);
+                println!("  generation:       {}", info.generation);
+                println!("  firmware version: {}", info.firmware_version);
+                println!("  streams:          {}", info.streams.join(", "));
+                println!("  capabilities:     {}", info.capabilities.join(", "));
+            }
+            Err(e) => println!("{}\n  could not query: {:?}", url, e),
+        }
+    }
+    Ok(())
+}
 
-    let url_c_string = CString::new(devices[0].clone()).unwrap();
-    let url_c = url_c_string.as_c_str();
-    let mut device_ptr: *mut Device = mem::zeroed();
-    let status = tobii_device_create(
-        api.ptr(),
-        url_c.as_ptr(),
-        &mut device_ptr as *mut *mut Device,
-    );
-    status_to_result(status)?;
-    let device = PtrWrapper::new(device_ptr, tobii_device_destroy);
+pub fn devices() {
+    if let Err(e) = unsafe { print_devices() } {
+        println!("Error: {:?}", e);
+    }
+}
 
-    let mut context = Box::new(CallbackContext { output });
-    let context_borrow = context.as_mut();
-    let status = tobii_gaze_point_subscribe(
-        device.ptr(),
-        Some(gaze_callback),
-        (context_borrow as *mut CallbackContext) as *mut raw::c_void,
-    );
-    let _subscription = PtrWrapper::new(device.ptr(), tobii_gaze_point_unsubscribe);
-    status_to_result(status)?;
+// Applies `action` and returns whether to shut down.
+fn handle(action: InputAction, paused: &Cell<bool>) -> bool {
+    match action {
+        InputAction::Pause => paused.set(true),
+        InputAction::Resume => paused.set(false),
+        InputAction::Shutdown => return true,
+    }
+    false
+}
 
-    /* HEAD POSE IS NOT SUPPORTED BY TRACKER !!!!!
-    let status = tobii_head_pose_subscribe(
-        device.ptr(),
-        Some(head_pose_callback),
-        (context_borrow as *mut CallbackContext) as *mut raw::c_void,
-    );
-    status_to_result(status)?;
-    */
+unsafe fn input_loop(
+    output: bus::Sender,
+    inbox: Receiver<InputAction>,
+    config: config::Tobii,
+) -> Result<(), TobiiError> {
+    let custom_log = CustomLog {
+        log_context: ptr::null_mut(),
+        log_func: Some(custom_log_fn),
+    };
+
+    println!("Initializing API!");
+    let api = PtrWrapper::new(create_api(&custom_log)?, tobii_api_destroy);
 
-    let status = tobii_gaze_origin_subscribe(
+    let paused = Cell::new(false);
+    let url = loop {
+        match select_device(api.ptr(), config.device.as_ref().map(String::as_str))? {
+            Some(url) => break url,
+            None if config.wait_for_device => {
+                // Keep looking, but stay responsive to actions.
+                match inbox.recv_timeout(DEVICE_POLL) {
+                    Ok(action) => {
+                        if handle(action, &paused) {
+                            return Ok(());
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                }
+            }
+            None => {
+                println!("No devices");
+                return Ok(());
+            }
+        }
+    };
+
+    println!("Using {}", url);
+    let device = PtrWrapper::new(create_device(api.ptr(), &url)?, tobii_device_destroy);
+
+    let mut context = Box::new(CallbackContext {
+        output,
+        eye: config.eye,
+        eye_tracked: Cell::new(true),
+        validity: Cell::new(None),
+        calibration_changed: Cell::new(false),
+        paused,
+        clock: RefCell::new(Clock::new()),
+    });
+    let context_borrow = context.as_mut();
+    let user_data = (context_borrow as *mut CallbackContext) as *mut raw::c_void;
+
+    let mut calibration = retrieve_calibration(device.ptr());
+    timesync(api.ptr(), device.ptr(), &context_borrow.clock);
+    let mut last_timesync = Instant::now();
+    let mut subscriptions = Some(connect(
         device.ptr(),
-        Some(gaze_origin_callback),
-        (context_borrow as *mut CallbackContext) as *mut raw::c_void,
-    );
-    status_to_result(status)?;
+        user_data,
+        config.eye,
+        &context_borrow.output,
+    )?);
 
     loop {
-        match inbox.try_recv() {
-            Ok(InputAction::Shutdown) => break,
-            Err(_) => (),
+        if let Ok(action) = inbox.try_recv() {
+            if handle(action, &context_borrow.paused) {
+                break;
+            }
         }
 
         let status = helpers::wait_for_device_callbacks(device.ptr());
-        match status_to_result(status) {
+        let result = match status_to_result(status) {
             Err(TobiiError::TimedOut) => continue,
-            Err(TobiiError::ConnectionFailed) => {
-                status_to_result(helpers::reconnect(device.ptr()))?;
+            Err(e) => Err(e),
+            Ok(()) => status_to_result(tobii_device_process_callbacks(device.ptr())),
+        };
+
+        let error = match result {
+            Ok(()) => {
+                if context_borrow.calibration_changed.replace(false) {
+                    calibration = retrieve_calibration(device.ptr()).or(calibration);
+                }
+                if last_timesync.elapsed() > TIMESYNC_INTERVAL {
+                    timesync(api.ptr(), device.ptr(), &context_borrow.clock);
+                    last_timesync = Instant::now();
+                }
                 continue;
             }
-            Err(e) => {
-                println!("erring after wait for dev callbacks!");
-                return Err(e);
+            Err(e) => e,
+        };
+
+        // Whatever went wrong, the connection is in an unknown state, so
+        // start over with fresh subscriptions.
+        println!("Lost eye tracker: {:?}", error);
+        let _ = context_borrow
+            .output
+            .send(Event::now(Input::TobiiConnection { connected: false }));
+        drop(subscriptions.take());
+
+        let mut backoff = RECONNECT_MIN;
+        while subscriptions.is_none() {
+            match inbox.recv_timeout(backoff) {
+                Ok(action) => {
+                    if handle(action, &context_borrow.paused) {
+                        return Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => (),
             }
-            Ok(()) => (),
-        }
+            backoff = min(backoff * 2, RECONNECT_MAX);
 
-        let status = tobii_device_process_callbacks(device.ptr());
-        if status == TOBII_ERROR_CONNECTION_FAILED {
-            status_to_result(helpers::reconnect(device.ptr()))?;
-            continue;
+            let result = status_to_result(helpers::reconnect(device.ptr())).and_then(|()| {
+                if let Some(ref calibration) = calibration {
+                    apply_calibration(device.ptr(), calibration);
+                }
+                // The tracker may have been restarted, along with its clock.
+                context_borrow.clock.borrow_mut().reset();
+                context_borrow.validity.set(None);
+                timesync(api.ptr(), device.ptr(), &context_borrow.clock);
+                last_timesync = Instant::now();
+                connect(device.ptr(), user_data, config.eye, &context_borrow.output)
+            });
+            match result {
+                Ok(s) => subscriptions = Some(s),
+                Err(e) => println!("Could not reconnect, retrying in {:?}: {:?}", backoff, e),
+            }
         }
-        status_to_result(status)?;
     }
     Ok(())
 }
 
-pub fn listen(output: SyncSender<Input>, inbox: Receiver<InputAction>) {
-    match unsafe { input_loop(output, inbox) } {
+pub fn listen(output: bus::Sender, inbox: Receiver<InputAction>, config: config::Tobii) {
+    match unsafe { input_loop(output, inbox, config) } {
         Ok(()) => (),
         Err(e) => {
             println!("Error: {:?}", e);
@@ -0,0 +1,387 @@
+// Magnifies the screen around the gaze, for selecting targets that are too
+// small to hit with gaze alone.
+//
+// The `zoom` action opens a lens: a window showing the area around the gaze,
+// enlarged by `factor`. The cursor keeps following the gaze, now over the
+// enlarged picture. The next click, whatever triggered it, closes the lens
+// and lands on the point of the screen that was shown under the cursor.
+// Triggering `zoom` again closes the lens without clicking.
+//
+// The picture is a still image taken when the lens opens.
+
+use cgmath::{vec2, Vector2};
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::xproto::{
+    ConfigureWindowAux, ConnectionExt as _, CreateGCAux, CreateWindowAux, Gcontext, ImageFormat,
+    Screen, StackMode, Window, WindowClass,
+};
+use x11rb::rust_connection::RustConnection;
+
+use std::error::Error;
+
+use crate::config;
+use crate::output::{Button, Output};
+
+// Position and size [px] of the enlarged area on the screen, and of the lens
+// showing it.
+#[derive(Clone, Copy, Debug)]
+struct Lens {
+    source: (i16, i16, u16, u16),
+    window: (i16, i16, u16, u16),
+}
+
+impl Lens {
+    // Point on the screen shown at `p` [px] on the lens, if `p` is on it.
+    fn map(&self, p: Vector2<f32>) -> Option<Vector2<f32>> {
+        let (x, y, width, height) = self.window;
+        let (sx, sy, sw, _) = self.source;
+        let (dx, dy) = (p.x - x as f32, p.y - y as f32);
+        if dx < 0f32 || dy < 0f32 || dx >= width as f32 || dy >= height as f32 {
+            return None;
+        }
+        let factor = width as f32 / sw as f32;
+        Some(vec2(sx as f32 + dx / factor, sy as f32 + dy / factor))
+    }
+}
+
+// Places an area of `size` centered on `center` within a screen of
+// `screen` size.
+fn place(center: Vector2<f32>, size: (u16, u16), screen: (u16, u16)) -> (i16, i16, u16, u16) {
+    let width = size.0.min(screen.0);
+    let height = size.1.min(screen.1);
+    let x = (center.x - width as f32 / 2f32)
+        .max(0f32)
+        .min((screen.0 - width) as f32);
+    let y = (center.y - height as f32 / 2f32)
+        .max(0f32)
+        .min((screen.1 - height) as f32);
+    (x as i16, y as i16, width, height)
+}
+
+struct LensWindow {
+    conn: RustConnection,
+    screen: Screen,
+    window: Window,
+    gc: Gcontext,
+    // Bytes per pixel of the screen in ZPixmap format.
+    pixel: usize,
+}
+
+impl LensWindow {
+    fn new() -> Result<LensWindow, Box<dyn Error>> {
+        let (conn, screen) = RustConnection::connect(None)?;
+        let screen = conn.setup().roots[screen].clone();
+        let depth = screen.root_depth;
+        let bits = conn
+            .setup()
+            .pixmap_formats
+            .iter()
+            .find(|format| format.depth == depth)
+            .map_or(0, |format| format.bits_per_pixel);
+        // Pictures are scaled by repeating whole pixels, and rows are taken
+        // to be unpadded, which holds for true color with 32 bits per pixel.
+        if (depth != 24 && depth != 32) || bits != 32 {
+            return Err(format!(
+                "unsupported visual: depth {}, {} bits per pixel",
+                depth, bits
+            )
+            .into());
+        }
+        let pixel = bits as usize / 8;
+
+        let window = conn.generate_id()?;
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            2,
+            WindowClass::INPUT_OUTPUT,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::new()
+                .border_pixel(screen.black_pixel)
+                .override_redirect(1),
+        )?;
+        let gc = conn.generate_id()?;
+        conn.create_gc(gc, window, &CreateGCAux::new())?;
+        conn.flush()?;
+
+        Ok(LensWindow {
+            conn,
+            screen,
+            window,
+            gc,
+            pixel,
+        })
+    }
+
+    fn open(&self, center: Vector2<f32>, config: &config::Zoom) -> Result<Lens, Box<dyn Error>> {
+        let factor = config.factor.max(1);
+        let screen = (self.screen.width_in_pixels, self.screen.height_in_pixels);
+        let window = place(center, (config.size[0], config.size[1]), screen);
+        let source = place(center, (window.2 / factor, window.3 / factor), screen);
+        // Fill the lens exactly, even if its size is not divisible by the
+        // factor.
+        let window = (window.0, window.1, source.2 * factor, source.3 * factor);
+
+        let image = self
+            .conn
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                self.screen.root,
+                source.0,
+                source.1,
+                source.2,
+                source.3,
+                !0,
+            )?
+            .reply()?;
+
+        // Nearest neighbour scaling, row by row.
+        let factor = factor as usize;
+        let row = source.2 as usize * self.pixel;
+        let mut rows = Vec::with_capacity(image.data.len() * factor * factor);
+        for line in image.data.chunks(row) {
+            let mut scaled = Vec::with_capacity(row * factor);
+            for pixel in line.chunks(self.pixel) {
+                for _ in 0..factor {
+                    scaled.extend_from_slice(pixel);
+                }
+            }
+            for _ in 0..factor {
+                rows.extend_from_slice(&scaled);
+            }
+        }
+
+        self.conn.configure_window(
+            self.window,
+            &ConfigureWindowAux::new()
+                .x(window.0 as i32)
+                .y(window.1 as i32)
+                .width(window.2 as u32)
+                .height(window.3 as u32)
+                .stack_mode(StackMode::ABOVE),
+        )?;
+        self.conn.map_window(self.window)?;
+
+        // Requests are limited in size, so send the picture in bands.
+        let stride = window.2 as usize * self.pixel;
+        let band = ((self.conn.maximum_request_bytes() - 64) / stride).max(1);
+        for (i, chunk) in rows.chunks(band * stride).enumerate() {
+            self.conn.put_image(
+                ImageFormat::Z_PIXMAP,
+                self.window,
+                self.gc,
+                window.2,
+                (chunk.len() / stride) as u16,
+                0,
+                (i * band) as i16,
+                0,
+                image.depth,
+                chunk,
+            )?;
+        }
+        self.conn.flush()?;
+
+        Ok(Lens { source, window })
+    }
+
+    fn close(&self) -> Result<(), Box<dyn Error>> {
+        self.conn.unmap_window(self.window)?;
+        // Wait for the window to be gone, or clicks may still land on it.
+        self.conn.get_input_focus()?.reply()?;
+        Ok(())
+    }
+}
+
+// Output that sends clicks through the lens, if it is open.
+pub struct Magnifier<O> {
+    pub inner: O,
+    // Where a click through the lens moved the cursor [px], since this was
+    // last taken.
+    pub warped: Option<Vector2<f32>>,
+    config: config::Zoom,
+    window: Option<LensWindow>,
+    lens: Option<Lens>,
+}
+
+impl<O: Output> Magnifier<O> {
+    pub fn new(inner: O, config: config::Zoom) -> Magnifier<O> {
+        let window = if config.enabled {
+            match LensWindow::new() {
+                Ok(window) => Some(window),
+                Err(e) => {
+                    println!("Could not create zoom window: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Magnifier {
+            inner,
+            warped: None,
+            config,
+            window,
+            lens: None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.lens.is_some()
+    }
+
+    // Opens the lens around `center` [px], or closes it.
+    pub fn toggle(&mut self, center: Vector2<f32>) {
+        if self.lens.is_some() {
+            self.close();
+            return;
+        }
+        let window = match self.window.as_ref() {
+            Some(window) => window,
+            None => return,
+        };
+        match window.open(center, &self.config) {
+            Ok(lens) => self.lens = Some(lens),
+            Err(e) => println!("Could not open zoom: {}", e),
+        }
+    }
+
+    fn close(&mut self) {
+        self.lens = None;
+        if let Some(window) = self.window.as_ref() {
+            if let Err(e) = window.close() {
+                println!("Could not close zoom: {}", e);
+            }
+        }
+    }
+}
+
+impl<O: Output> Output for Magnifier<O> {
+    fn move_to(&mut self, x: i32, y: i32) {
+        self.inner.move_to(x, y);
+    }
+
+    fn move_relative(&mut self, dx: f32, dy: f32) {
+        self.inner.move_relative(dx, dy);
+    }
+
+    fn location(&mut self) -> Option<(i32, i32)> {
+        self.inner.location()
+    }
+
+    fn press(&mut self, button: Button) {
+        if let Some(lens) = self.lens {
+            self.close();
+            let target = self
+                .inner
+                .location()
+                .and_then(|(x, y)| lens.map(vec2(x as f32, y as f32)));
+            if let Some(target) = target {
+                self.inner.move_to(target.x as i32, target.y as i32);
+                self.warped = Some(target);
+            }
+        }
+        self.inner.press(button);
+    }
+
+    fn release(&mut self, button: Button) {
+        self.inner.release(button);
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        self.inner.scroll(dx, dy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: (u16, u16) = (1920, 1080);
+
+    #[test]
+    fn place_centers() {
+        assert_eq!(place(vec2(960f32, 540f32), (400, 300), SCREEN), (760, 390, 400, 300));
+    }
+
+    #[test]
+    fn place_clamps_to_the_edges() {
+        assert_eq!(place(vec2(10f32, 20f32), (400, 300), SCREEN), (0, 0, 400, 300));
+        assert_eq!(place(vec2(1910f32, 1070f32), (400, 300), SCREEN), (1520, 780, 400, 300));
+        // Larger than the screen.
+        assert_eq!(place(vec2(100f32, 100f32), (4000, 3000), SCREEN), (0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn map_through_the_lens() {
+        // A 200 x 100 px area at (100, 50) enlarged 3 times.
+        let lens = Lens {
+            source: (100, 50, 200, 100),
+            window: (0, 0, 600, 300),
+        };
+        assert_eq!(lens.map(vec2(0f32, 0f32)), Some(vec2(100f32, 50f32)));
+        assert_eq!(lens.map(vec2(300f32, 150f32)), Some(vec2(200f32, 100f32)));
+        assert_eq!(lens.map(vec2(597f32, 297f32)), Some(vec2(299f32, 149f32)));
+        // Off the lens.
+        assert_eq!(lens.map(vec2(600f32, 150f32)), None);
+        assert_eq!(lens.map(vec2(300f32, -1f32)), None);
+    }
+
+    // Cursor that only moves.
+    struct Cursor(i32, i32);
+
+    impl Output for Cursor {
+        fn move_to(&mut self, x: i32, y: i32) {
+            *self = Cursor(x, y);
+        }
+
+        fn move_relative(&mut self, _: f32, _: f32) {}
+
+        fn location(&mut self) -> Option<(i32, i32)> {
+            Some((self.0, self.1))
+        }
+
+        fn press(&mut self, _: Button) {}
+
+        fn release(&mut self, _: Button) {}
+
+        fn scroll(&mut self, _: i32, _: i32) {}
+    }
+
+    #[test]
+    fn clicks_through_the_lens() {
+        let config = config::Zoom {
+            enabled: false,
+            ..config::Zoom::default()
+        };
+        let mut magnifier = Magnifier::new(Cursor(300, 150), config);
+        magnifier.lens = Some(Lens {
+            source: (100, 50, 200, 100),
+            window: (0, 0, 600, 300),
+        });
+        magnifier.click(Button::Left);
+        assert!(!magnifier.is_open());
+        assert_eq!((magnifier.inner.0, magnifier.inner.1), (200, 100));
+        assert_eq!(magnifier.warped.take(), Some(vec2(200f32, 100f32)));
+
+        // Without the lens, clicks stay where they are.
+        magnifier.click(Button::Left);
+        assert_eq!((magnifier.inner.0, magnifier.inner.1), (200, 100));
+        assert_eq!(magnifier.warped, None);
+    }
+
+    #[test]
+    fn map_an_offset_lens() {
+        let lens = Lens {
+            source: (900, 500, 100, 50),
+            window: (800, 450, 400, 200),
+        };
+        assert_eq!(lens.map(vec2(800f32, 450f32)), Some(vec2(900f32, 500f32)));
+        assert_eq!(lens.map(vec2(1000f32, 550f32)), Some(vec2(950f32, 525f32)));
+        assert_eq!(lens.map(vec2(799f32, 500f32)), None);
+    }
+}